@@ -2,9 +2,12 @@
 use crossbeam::channel::{Sender, Receiver, unbounded};
 use crossbeam::sync::WaitGroup;
 
-use fluxcore::job::*;
-use fluxcore::constants::DEFAULT_PORT;
-use fluxcore::manager::{Worker, WorkerHandle, RenderEvent, WorkerInfo};
+use fluxcore::constants::{DEFAULT_PORT, DEFAULT_DISCOVERY_PORT};
+use fluxcore::discovery::{NodeAnnouncement, serve_discovery};
+use fluxcore::manager::{Worker, WorkerHandle, RenderEvent, WorkerInfo, WorkQueue};
+use fluxcore::protocol::*;
+use fluxcore::encoding::{PixelEncoding, WireEncoding, encode_event};
+use fluxcore::net::{NetStream, ServerSecurity, load_secret, server_tls_config};
 use fluxcore::workers::*;
 
 use serde_cbor::StreamDeserializer;
@@ -13,28 +16,102 @@ use serde_cbor::to_writer;
 
 use std::net::{TcpListener, TcpStream};
 use std::thread;
+use std::sync::{Arc, Mutex};
 use std::io;
 use std::str::FromStr;
+use std::time::Duration;
 
 use clap::{Arg, App};
 
-fn handle_client(stream: TcpStream, worker: &WorkerHandle, config: &Config) -> io::Result<()> {
-    let peer = stream.peer_addr()?;
+const DEFAULT_MAX_CLIENTS: usize = 4;
 
-    println!("Got connection from {}", peer);
+// How long a connecting manager has to complete each step of the TLS
+// and protocol handshakes before the node gives up on it.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+// The name of this host, for identifying the node to managers.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
 
-    let worker_info = WorkerInfo {
-        num_threads: config.num_threads,
+// Exchange hellos with a manager. Returns the encoding to send results
+// in and the client slot taken for the manager if it was accepted; a
+// manager speaking a different protocol is refused with a reason it can
+// report. So is a manager that cannot prove it knows the node's shared
+// secret, and an acceptable manager when all of the slots are taken.
+fn handshake(stream: &mut NetStream, config: &Config, slots: &ClientSlots) -> io::Result<Option<(WireEncoding, ClientSlot)>> {
+    let challenge = config.security.secret.as_ref().map(|_| new_auth_challenge());
+
+    let hello = NodeHello {
+        protocol_version: PROTOCOL_VERSION,
+        flux_version: FLUX_VERSION.to_string(),
+        hostname: hostname(),
+        features: Features::supported(),
+        worker_info: WorkerInfo {
+            num_threads: config.num_threads,
+        },
+        auth_challenge: challenge.clone(),
+        pixel_encodings: PixelEncoding::all(),
+        compression: true,
     };
+    write_message(stream, &hello)?;
 
-    let mut owned_stream = stream;
-    to_writer(&mut owned_stream, &worker_info).unwrap();
+    let manager: ManagerHello = read_message(stream)?;
+    let verdict = check_protocol_version("manager", manager.protocol_version, &manager.flux_version)
+        .and_then(|()| check_auth(config, challenge, manager.auth_response.clone()))
+        .and_then(|()| {
+            if hello.supports(&manager.wire_encoding) {
+                Ok(())
+            } else {
+                Err(format!("cannot send results as {:?}", manager.wire_encoding))
+            }
+        })
+        .and_then(|()| {
+            slots.take().ok_or_else(|| format!("node is busy with its limit of {} clients", slots.max_clients))
+        });
+
+    match verdict {
+        Ok(slot) => {
+            write_message(stream, &NodeGreeting::Accepted)?;
+            Ok(Some((manager.wire_encoding, slot)))
+        },
+        Err(reason) => {
+            println!("Refusing connection: {}", reason);
+            write_message(stream, &NodeGreeting::Refused { reason })?;
+            Ok(None)
+        },
+    }
+}
+
+fn check_auth(config: &Config, challenge: Option<Vec<u8>>, response: Option<Vec<u8>>) -> Result<(), String> {
+    match (&config.security.secret, challenge, response) {
+        (None, _, _) => Ok(()),
+        (Some(_), _, None) => Err("authentication required, but the manager has no shared secret".to_string()),
+        (Some(secret), Some(challenge), Some(response)) => {
+            if verify_auth_response(secret, &challenge, &response) {
+                Ok(())
+            } else {
+                Err("authentication failed, the shared secret does not match".to_string())
+            }
+        },
+        (Some(_), None, Some(_)) => panic!("check_auth: node has a secret but sent no challenge"),
+    }
+}
+
+fn handle_client(stream: NetStream, worker: &WorkerHandle, encoding: WireEncoding) -> io::Result<()> {
+    let owned_stream = stream;
 
     let thread_stream = owned_stream.try_clone().unwrap();
-    let stream_de: StreamDeserializer<'_, IoRead<TcpStream>, NetworkWorkerRequest> =
+    let stream_de: StreamDeserializer<'_, IoRead<NetStream>, NetworkWorkerRequest> =
         StreamDeserializer::new(IoRead::new(owned_stream));
 
-    let (wu_send, wu_recv): (Sender<WorkUnit>, Receiver<WorkUnit>) = unbounded();
     let (re_send, re_recv): (Sender<Option<RenderEvent>>, Receiver<Option<RenderEvent>>) = unbounded();
     let wg = WaitGroup::new();
 
@@ -43,7 +120,12 @@ fn handle_client(stream: TcpStream, worker: &WorkerHandle, config: &Config) -> i
         println!("Work unit result thread started");
         while let Ok(Some(ev)) = re_recv.recv() {
             println!("Got results from local worker, sending to manager");
-            match to_writer(&mut my_stream, &ev) {
+            let written = if encoding.is_compact() {
+                to_writer(&mut my_stream, &encode_event(ev, encoding))
+            } else {
+                to_writer(&mut my_stream, &ev)
+            };
+            match written {
                 Ok(()) => (),
                 Err(e) => {
                     println!("Manager connection error: {}", e);
@@ -54,13 +136,22 @@ fn handle_client(stream: TcpStream, worker: &WorkerHandle, config: &Config) -> i
         println!("Work unit result thread stopping");
     });
 
+    // The queue for the current job, which the manager fills one work
+    // unit at a time
+    let mut queue: Option<Arc<WorkQueue>> = None;
+
     for result in stream_de {
         match result {
             Ok(req) => {
                 match req {
                     NetworkWorkerRequest::SetJob(j) => {
                         println!("Got job");
-                        let send_result = worker.send(j, wu_recv.clone(), re_send.clone(), wg.clone());
+                        if let Some(q) = queue.take() {
+                            q.close();
+                        }
+                        let q = WorkQueue::open();
+                        let send_result = worker.send(j, q.handle(0), re_send.clone(), wg.clone());
+                        queue = Some(q);
                         match send_result {
                             Ok(()) => {
                                 println!("Sent job to local worker");
@@ -72,20 +163,42 @@ fn handle_client(stream: TcpStream, worker: &WorkerHandle, config: &Config) -> i
                     },
                     NetworkWorkerRequest::WorkUnit(u) => {
                         println!("Got work unit, sending to worker");
-                        wu_send.send(u).unwrap();
+                        match &queue {
+                            Some(q) => q.push(u),
+                            None => println!("Got work unit before any job, ignoring it"),
+                        }
+                    },
+                    NetworkWorkerRequest::Withdraw(u) => {
+                        println!("Got work unit withdrawal");
+                        if let Some(q) = &queue {
+                            q.withdraw(&u);
+                        }
                     },
                     NetworkWorkerRequest::Done => {
-                        println!("Got done message, shutting down");
-                        return Ok(())
+                        println!("Got done message, job finished");
+                        if let Some(q) = queue.take() {
+                            q.close();
+                        }
                     }
                 }
             },
             Err(err) => {
+                // The manager is gone, so abandon any work it had sent
+                if let Some(q) = queue.take() {
+                    q.cancel();
+                }
+                drop(re_send);
+                t_handle.join().unwrap();
                 return Err(io::Error::new(io::ErrorKind::Other, format!("{}", err)));
             }
         }
     }
 
+    println!("Manager disconnected");
+    if let Some(q) = queue.take() {
+        q.cancel();
+    }
+
     drop(re_send);
 
     t_handle.join().unwrap();
@@ -93,27 +206,158 @@ fn handle_client(stream: TcpStream, worker: &WorkerHandle, config: &Config) -> i
     Ok(())
 }
 
-fn run_server(bind_address: String, worker: &LocalWorker, config: &Config) -> io::Result<()> {
+// The node's limited number of slots for managers to render for.
+#[derive(Clone)]
+struct ClientSlots {
+    active_clients: Arc<Mutex<usize>>,
+    max_clients: usize,
+}
+
+impl ClientSlots {
+    fn new(max_clients: usize) -> Self {
+        Self {
+            active_clients: Arc::new(Mutex::new(0)),
+            max_clients,
+        }
+    }
+
+    // Take a slot if one is free.
+    fn take(&self) -> Option<ClientSlot> {
+        let mut n = self.active_clients.lock().unwrap();
+        if *n < self.max_clients {
+            *n += 1;
+            println!("{} of at most {} clients active", *n, self.max_clients);
+            Some(ClientSlot {
+                active_clients: Arc::clone(&self.active_clients),
+                max_clients: self.max_clients,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+// Holds one of the node's client slots, releasing it when dropped.
+struct ClientSlot {
+    active_clients: Arc<Mutex<usize>>,
+    max_clients: usize,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut n = self.active_clients.lock().unwrap();
+        *n -= 1;
+        println!("{} of at most {} clients active", *n, self.max_clients);
+    }
+}
+
+fn run_client(stream: TcpStream, config: &Config, slots: &ClientSlots) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    println!("Got connection from {}", peer);
+
+    // Managers that connect and then say nothing must not keep their
+    // thread forever; once accepted, they may be idle between jobs.
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+
+    let mut owned_stream = match &config.security.tls {
+        None => NetStream::plain(stream),
+        Some(tls) => {
+            match NetStream::accept_tls(stream, tls) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("TLS handshake with {} failed: {}", peer, e);
+                    return Ok(());
+                },
+            }
+        },
+    };
+
+    let (encoding, slot) = match handshake(&mut owned_stream, config, slots) {
+        Ok(None) => return Ok(()),
+        Ok(Some(accepted)) => accepted,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            println!("{} did not complete the handshake in time", peer);
+            return Ok(());
+        },
+        Err(e) => return Err(e),
+    };
+    owned_stream.socket().set_read_timeout(None)?;
+    println!("Sending results to {} as {:?}", peer, encoding);
+
+    let worker = LocalWorker::sharing_pool();
+    let result = handle_client(owned_stream, &worker.handle(), encoding);
+    worker.stop();
+    drop(slot);
+
+    println!("Connection from {} closed", peer);
+    result
+}
+
+fn run_server(bind_address: String, config: &Config) -> io::Result<()> {
     let listener = TcpListener::bind(bind_address)?;
-    let handle = worker.handle();
+    let slots = ClientSlots::new(config.max_clients);
 
     for stream in listener.incoming() {
-        match handle_client(stream?, &handle, &config) {
-            Ok(()) => {
-            },
+        let stream = match stream {
+            Ok(s) => s,
             Err(e) => {
-                println!("run_server: handle_client exited with {}", e);
+                println!("run_server: could not accept connection: {}", e);
+                continue;
+            },
+        };
+
+        // Each client gets its own thread and worker, and the workers
+        // share the global thread pool. Clients beyond the limit are
+        // refused once they have completed the handshake.
+        let client_config = config.clone();
+        let client_slots = slots.clone();
+        thread::Builder::new().name("Client".to_string()).spawn(move || {
+            if let Err(e) = run_client(stream, &client_config, &client_slots) {
+                println!("run_server: client exited with {}", e);
             }
-        }
+        })?;
     }
 
     Ok(())
 }
 
+// Answer discovery probes in the background. Failing to do so only
+// means that managers must name this node explicitly.
+fn start_discovery(port: u16, listen_port: u16, config: &Config) -> io::Result<()> {
+    let announcement = NodeAnnouncement {
+        protocol_version: PROTOCOL_VERSION,
+        flux_version: FLUX_VERSION.to_string(),
+        hostname: hostname(),
+        port: listen_port,
+        worker_info: WorkerInfo {
+            num_threads: config.num_threads,
+        },
+        tls: config.security.tls.is_some(),
+        auth: config.security.secret.is_some(),
+    };
+
+    println!("Answering discovery probes on UDP port {}", port);
+
+    thread::Builder::new().name("Discovery".to_string()).spawn(move || {
+        if let Err(e) = serve_discovery(port, announcement) {
+            println!("Discovery stopped: {}", e);
+        }
+    })?;
+
+    Ok(())
+}
+
+#[derive(Clone)]
 struct Config {
     pub listen_host: String,
     pub listen_port: String,
     pub num_threads: usize,
+    pub max_clients: usize,
+    pub secret_file: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub security: ServerSecurity,
+    pub discovery_port: Option<u16>,
 }
 
 fn config_from_args() -> Config {
@@ -135,7 +379,39 @@ fn config_from_args() -> Config {
              .short("t")
              .long("threads")
              .help("Number of rendering threads (defaults to number of logical CPUs)")
-             .takes_value(true));
+             .takes_value(true))
+        .arg(Arg::with_name("max_clients")
+             .short("c")
+             .long("max-clients")
+             .value_name("COUNT")
+             .help("Maximum number of managers to render for at once, sharing the rendering threads; further connections are refused (defaults to 4)")
+             .takes_value(true))
+        .arg(Arg::with_name("secret_file")
+             .long("secret-file")
+             .value_name("FILENAME")
+             .help("Require managers to authenticate with the shared secret in this file")
+             .takes_value(true))
+        .arg(Arg::with_name("tls_cert")
+             .long("tls-cert")
+             .value_name("FILENAME")
+             .help("Accept only TLS connections, using the PEM certificate chain in this file (requires --tls-key)")
+             .requires("tls_key")
+             .takes_value(true))
+        .arg(Arg::with_name("tls_key")
+             .long("tls-key")
+             .value_name("FILENAME")
+             .help("PEM private key for the --tls-cert certificate")
+             .requires("tls_cert")
+             .takes_value(true))
+        .arg(Arg::with_name("discovery_port")
+             .long("discovery-port")
+             .value_name("PORT")
+             .help("Answer discovery probes from managers on this UDP port (defaults to 2001)")
+             .takes_value(true))
+        .arg(Arg::with_name("no_discovery")
+             .long("no-discovery")
+             .help("Do not answer discovery probes; managers must name this node to use it")
+             .conflicts_with("discovery_port"));
 
     let ms = app.get_matches();
     let default_host = "0.0.0.0";
@@ -148,19 +424,48 @@ fn config_from_args() -> Config {
             None => num_cpus::get(),
             Some(t) => usize::from_str(t).unwrap(),
         },
+        max_clients: match ms.value_of("max_clients") {
+            None => DEFAULT_MAX_CLIENTS,
+            Some(c) => usize::from_str(c).unwrap(),
+        },
+        secret_file: ms.value_of("secret_file").map(|s| s.to_string()),
+        tls_cert: ms.value_of("tls_cert").map(|s| s.to_string()),
+        tls_key: ms.value_of("tls_key").map(|s| s.to_string()),
+        security: ServerSecurity::default(),
+        discovery_port: match ms.occurrences_of("no_discovery") {
+            0 => Some(ms.value_of("discovery_port").map_or(DEFAULT_DISCOVERY_PORT, |p| u16::from_str(p).unwrap())),
+            _ => None,
+        },
     }
 }
 
 fn main() -> io::Result<()> {
-    let config = config_from_args();
-    let bind_address = format!("{}:{}", config.listen_host, config.listen_port);
+    let mut config = config_from_args();
 
-    println!("Bind address: {}", bind_address);
+    if let Some(path) = &config.secret_file {
+        let secret = load_secret(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("could not read secret file {}: {}", path, e)))?;
+        config.security.secret = Some(secret);
+        println!("Requiring managers to authenticate");
+    }
 
-    let worker = LocalWorker::new(config.num_threads);
-    run_server(bind_address, &worker, &config)?;
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        let tls = server_tls_config(cert, key)
+            .map_err(|e| io::Error::new(e.kind(), format!("could not load TLS certificate: {}", e)))?;
+        config.security.tls = Some(tls);
+        println!("Accepting only TLS connections");
+    }
 
-    worker.stop();
+    let listen_port = u16::from_str(&config.listen_port)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port {}: {}", config.listen_port, e)))?;
+    let bind_address = format!("{}:{}", config.listen_host, listen_port);
 
-    Ok(())
+    println!("Bind address: {}", bind_address);
+
+    if let Some(port) = config.discovery_port {
+        start_discovery(port, listen_port, &config)?;
+    }
+
+    LocalWorker::configure_thread_pool(config.num_threads);
+    run_server(bind_address, &config)
 }
@@ -1,7 +1,10 @@
 
+mod scene_file;
+
 use std::time::Duration;
 use std::str::FromStr;
 use std::process::exit;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::event::Event;
@@ -10,26 +13,119 @@ use sdl2::image::{INIT_PNG, INIT_JPG};
 
 use fluxcore::manager::*;
 use fluxcore::workers::{LocalWorker, NetworkWorker};
-use fluxcore::job::JobConfiguration;
+use fluxcore::net::{ClientSecurity, load_secret, client_tls_config};
+use fluxcore::checkpoint::{Checkpoint, Checkpointer};
+use fluxcore::progress::{ProgressFormat, ProgressReporter};
+use fluxcore::encoding::{PixelEncoding, WireEncoding};
+use fluxcore::discovery::{DiscoveredNode, discover_nodes};
+use fluxcore::constants::{DEFAULT_PORT, DEFAULT_DISCOVERY_PORT};
+use fluxcore::protocol::PROTOCOL_VERSION;
+use fluxcore::job::{Job, JobConfiguration, TileOrder};
 use fluxcore::scene::*;
+use fluxcore::image::{ImageFormat, ImageOutput};
+use fluxcore::tonemap::ToneMapper;
+
+use scene_file::read_scene;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
+use crossbeam::channel::Sender;
 
 use std::fs::File;
+use std::io;
+use std::io::Write;
 
 const DEFAULT_SAMPLE_ROOT: usize = 1;
 const DEFAULT_DEPTH: usize = 5;
+const DEFAULT_NODE_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_DISCOVER_SECS: f64 = 2.0;
+const DEFAULT_CHECKPOINT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_PROGRESS_INTERVAL_SECS: u64 = 10;
+// With adaptive sampling, pixels may take up to this many times the
+// per-pass sample count unless --max-samples says otherwise.
+const DEFAULT_MAX_SAMPLES_FACTOR: usize = 16;
 
 fn main() {
     // Get the configuration from the command-line arguments
     let config = config_from_args();
 
-    // Load the YAML scene file
-    let scene_file = File::open(config.input_filename).unwrap();
-    let s: SceneData = serde_yaml::from_reader(scene_file).unwrap();
+    // Resume a job from a checkpoint, or load the YAML scene file. A
+    // checkpoint contains the scene, so its files are not needed.
+    let resumed = config.resume_filename.as_ref().map(|path| {
+        match Checkpoint::read(path) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("Could not resume from {}: {}", path, e);
+                exit(1);
+            }
+        }
+    });
+
+    let s = match &resumed {
+        Some(c) => c.job.scene_data.clone(),
+        None => load_scene(config.input_filename.as_ref().unwrap()),
+    };
+
+    // Determine the output file and format. An explicit format takes
+    // precedence over the output file's extension. A resumed job goes
+    // where it was going unless told otherwise.
+    let (output_path, output_format, mut tone_mapping) = match &resumed {
+        Some(c) if config.output_filename.is_none() && config.output_format.is_none() => {
+            (c.output.path.clone(), c.output.format, c.output.tone_mapping)
+        },
+        _ => {
+            let output_format = match (config.output_format, &config.output_filename) {
+                (Some(f), _) => f,
+                (None, None) => ImageFormat::Ppm,
+                (None, Some(path)) => match ImageFormat::from_path(path) {
+                    Some(f) => f,
+                    None => {
+                        println!("Cannot determine image format of '{}', please specify one with --format", path);
+                        exit(1);
+                    },
+                },
+            };
+            let output_path = match &config.output_filename {
+                Some(path) => path.clone(),
+                None => format!("{}.{}", s.scene_name, output_format.extension()),
+            };
+            (output_path, output_format, s.output_settings.tone_mapping)
+        },
+    };
+
+    // Command-line tone mapping settings override the scene's
+    if let Some(op) = config.tone_mapper {
+        tone_mapping.operator = op;
+    }
+    if let Some(e) = config.exposure {
+        tone_mapping.exposure = e;
+    }
+    if config.linear_output {
+        tone_mapping.srgb = false;
+    }
+
+    let output = ImageOutput {
+        path: output_path,
+        format: output_format,
+        tone_mapping,
+    };
+
+    // Find network nodes, if requested
+    let discovered = match config.discover_window {
+        None => vec![],
+        Some(window) => {
+            println!("Discovering network nodes for {:.1}s", window.as_secs_f64());
+            match discover_nodes(config.discovery_port, window) {
+                Ok(nodes) => usable_nodes(nodes, &config),
+                Err(e) => {
+                    println!("Could not discover network nodes: {}", e);
+                    vec![]
+                }
+            }
+        }
+    };
 
     // Check that we have at least one worker
-    if !config.use_local_worker && config.network_workers.is_empty() {
+    if !config.use_local_worker && config.network_workers.is_empty() && discovered.is_empty() {
         println!("No workers specified, exiting");
         return;
     }
@@ -48,15 +144,45 @@ fn main() {
     }
 
     // Connect to network workers, if any
-    for endpoint in config.network_workers {
+    let mut security = ClientSecurity::default();
+
+    if let Some(path) = &config.secret_file {
+        match load_secret(path) {
+            Ok(secret) => security.secret = Some(secret),
+            Err(e) => {
+                println!("Could not read secret file {}: {}", path, e);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &config.tls_ca {
+        match client_tls_config(path) {
+            Ok(tls) => security.tls = Some(tls),
+            Err(e) => {
+                println!("Could not load TLS CA certificate: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    // Nodes named on the command line must be reachable, but discovered
+    // nodes are only used if they can be
+    let endpoints = config.network_workers.iter().map(|e| (e.clone(), true))
+        .chain(discovered.into_iter().map(|e| (e, false)));
+
+    for (endpoint, required) in endpoints {
         println!("Connecting to {}", &endpoint);
-        match NetworkWorker::new(&endpoint) {
+        match NetworkWorker::new(&endpoint, config.node_timeout, &security, config.wire_encoding) {
             Err(e) => {
                 println!("Could not connect network node '{}': {}", endpoint, e);
-                exit(1);
+                if required {
+                    exit(1);
+                }
             }
             Ok(worker) => {
-                println!("Network worker ready, info:");
+                println!("Network worker ready on {} (flux {}, results as {:?}), info:",
+                         worker.node().hostname, worker.node().flux_version, worker.encoding());
                 worker.info().print();
 
                 worker_handles.push(worker.handle());
@@ -69,30 +195,89 @@ fn main() {
     println!("Starting rendering manager");
     let mut manager = RenderManager::new(worker_handles);
 
-    // Build a job configuration from the local config
-    let jobcfg = JobConfiguration {
-        rows_per_work_unit: config.rows_per_work_unit,
-        max_trace_depth: config.max_depth,
-        sample_root: config.sample_root,
+    // Build a job configuration from the local config, unless resuming
+    // a job that already has one
+    let jobcfg = match &resumed {
+        Some(c) => {
+            println!("Resuming job {:?} from {}, {} work units already done",
+                     c.job.id, config.resume_filename.as_ref().unwrap(), c.units_done());
+            c.job.config
+        },
+        None => JobConfiguration {
+            tile_size: config.tile_size,
+            tile_order: config.tile_order,
+            max_trace_depth: config.max_depth,
+            sample_root: config.sample_root,
+            // Adaptive sampling only works in a single pass
+            passes: config.passes.unwrap_or(
+                if config.show_live_preview && config.noise_threshold <= 0.0 { 0 } else { 1 }),
+            noise_threshold: config.noise_threshold,
+            min_samples: config.min_samples.unwrap_or(config.sample_root * config.sample_root),
+            max_samples: config.max_samples.unwrap_or(
+                DEFAULT_MAX_SAMPLES_FACTOR * config.sample_root * config.sample_root),
+        },
+    };
+
+    let problems = jobcfg.validate();
+    if !problems.is_empty() {
+        for p in &problems {
+            println!("Invalid job configuration: {}", p);
+        }
+        exit(1);
+    }
+
+    // Checkpoints go to the requested file, or back to the one being
+    // resumed from
+    let remaining = resumed.as_ref().map(|c| (c.job.clone(), c.remaining_work_units()));
+    let already_done = resumed.as_ref().map_or((0, 0), |c| (c.pixels_done(), c.units_done()));
+    let checkpoint_path = config.checkpoint_filename.clone().or_else(|| config.resume_filename.clone());
+    let checkpointer = checkpoint_path.map(|path| match resumed {
+        Some(c) => Checkpointer::resume(path, config.checkpoint_interval, c),
+        None => Checkpointer::new(path, config.checkpoint_interval, &s, jobcfg, output.clone()),
+    });
+    let builder_options = ImageBuilderOptions {
+        checkpointer,
+        partial_interval: config.partial_interval,
     };
 
     if config.show_live_preview {
         // If the live preview was requested, create an SDL window and
         // update it from the image accumulator
-        show_preview(&mut manager, &s, jobcfg);
+        show_preview(&mut manager, &s, jobcfg, &output, builder_options, remaining);
     } else {
         // Else the live preview was not requested, so just block until
         // the job completes.
+        let image_builder = ImageBuilder::with_options(output.clone(), builder_options);
+
+        // If progress reports were requested, the reporter sees the
+        // job's results on their way to the image builder
+        let reporter = config.progress_format.map(|format| {
+            let out: Box<dyn Write + Send> = match &config.progress_filename {
+                None => Box::new(io::stdout()),
+                Some(path) => match File::create(path) {
+                    Ok(f) => Box::new(f),
+                    Err(e) => {
+                        println!("Could not create progress file {}: {}", path, e);
+                        exit(1);
+                    },
+                },
+            };
+            ProgressReporter::new(format, config.progress_interval, jobcfg.passes, already_done.0, already_done.1,
+                                  out, image_builder.sender())
+        });
+        let results = match &reporter {
+            Some(r) => r.sender(),
+            None => image_builder.sender(),
+        };
 
-        // Start an image accumulator thread
-        let image_builder = ImageBuilder::new();
-
-        // Submit the job to the rendering manager
         println!("Sending job to rendering manager");
-        let job = manager.schedule_job(&s, jobcfg, image_builder.sender());
+        let job = submit_job(&mut manager, &s, jobcfg, remaining, results);
 
         job.wait();
 
+        if let Some(r) = reporter {
+            r.stop();
+        }
         image_builder.stop();
     }
 
@@ -111,25 +296,146 @@ fn main() {
     manager.stop();
 }
 
+fn load_scene(filename: &str) -> SceneData {
+    match read_scene(filename) {
+        Ok(s) => s,
+        Err(errors) => {
+            for e in &errors {
+                println!("{}", e);
+            }
+            exit(1);
+        },
+    }
+}
+
+// Report every problem with a scene file without rendering it, exiting
+// with an error status if there are any.
+fn check_scene(filename: &str) -> ! {
+    match read_scene(filename) {
+        Ok(_) => {
+            println!("{}: OK", filename);
+            exit(0);
+        },
+        Err(errors) => {
+            for e in &errors {
+                println!("{}", e);
+            }
+            println!("{}: {} problem{} found", filename, errors.len(), if errors.len() == 1 { "" } else { "s" });
+            exit(1);
+        },
+    }
+}
+
+// Submit the job, or the rest of a resumed one, to the rendering
+// manager.
+fn submit_job(manager: &mut RenderManager, s: &SceneData, jobcfg: JobConfiguration,
+              remaining: Option<(Job, WorkUnits)>, results: Sender<Option<RenderEvent>>) -> JobHandle {
+    match remaining {
+        None => manager.schedule_job(s, jobcfg, results),
+        Some((job, units)) => manager.resume_job(job, units, results),
+    }
+}
+
+// The endpoints of the discovered nodes that we can render with and
+// that were not already named on the command line.
+fn usable_nodes(nodes: Vec<DiscoveredNode>, config: &Config) -> Vec<String> {
+    let mut endpoints = vec![];
+
+    // Discovered nodes are known by address, so resolve the nodes named
+    // with --node to recognise them whichever name was used
+    let named_addresses: Vec<SocketAddr> = config.network_workers.iter()
+        .flat_map(|e| {
+            let endpoint = match e.find(':') {
+                None => format!("{}:{}", e, DEFAULT_PORT),
+                Some(_) => e.clone(),
+            };
+            // A name that does not resolve is reported when connecting
+            match endpoint.to_socket_addrs() {
+                Ok(addrs) => addrs.collect(),
+                Err(_) => vec![],
+            }
+        })
+        .collect();
+
+    for node in nodes {
+        let a = &node.announcement;
+        let endpoint = node.address.to_string();
+        println!("Discovered node {} at {} (flux {}, {} threads{}{})",
+                 a.hostname, endpoint, a.flux_version, a.worker_info.num_threads,
+                 if a.tls { ", TLS" } else { "" },
+                 if a.auth { ", authenticated" } else { "" });
+
+        let named = named_addresses.contains(&node.address);
+
+        let problem = if named {
+            Some("it was already named with --node")
+        } else if a.protocol_version != PROTOCOL_VERSION {
+            Some("it speaks a different protocol version")
+        } else if a.tls && config.tls_ca.is_none() {
+            Some("it requires TLS (--tls-ca)")
+        } else if a.auth && config.secret_file.is_none() {
+            Some("it requires a shared secret (--secret-file)")
+        } else {
+            None
+        };
+
+        match problem {
+            None => endpoints.push(endpoint),
+            Some(p) => println!("  Skipping it: {}", p),
+        }
+    }
+
+    endpoints
+}
+
 #[derive(Debug)]
 struct Config {
     network_workers: Vec<String>,
+    node_timeout: Option<Duration>,
+    secret_file: Option<String>,
+    tls_ca: Option<String>,
+    discover_window: Option<Duration>,
+    discovery_port: u16,
+    wire_encoding: WireEncoding,
     use_local_worker: bool,
     sample_root: usize,
+    passes: Option<usize>,
+    noise_threshold: f64,
+    min_samples: Option<usize>,
+    max_samples: Option<usize>,
     max_depth: usize,
-    rows_per_work_unit: usize,
-    input_filename: String,
+    tile_size: usize,
+    tile_order: TileOrder,
+    input_filename: Option<String>,
+    resume_filename: Option<String>,
+    checkpoint_filename: Option<String>,
+    checkpoint_interval: Duration,
+    partial_interval: Option<Duration>,
+    progress_format: Option<ProgressFormat>,
+    progress_interval: Duration,
+    progress_filename: Option<String>,
     show_live_preview: bool,
     num_threads: usize,
+    output_filename: Option<String>,
+    output_format: Option<ImageFormat>,
+    tone_mapper: Option<ToneMapper>,
+    exposure: Option<f64>,
+    linear_output: bool,
 }
 
 fn config_from_args() -> Config {
     let app = App::new("flux")
         .author("Jonathan Daugherty <cygnus@foobox.com>")
         .about("Flux ray tracer")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("check")
+                    .about("Check a scene file for problems without rendering it")
+                    .arg(Arg::with_name("scene_file")
+                         .index(1)
+                         .required(true)))
         .arg(Arg::with_name("scene_file")
              .index(1)
-             .required(true))
+             .required_unless("resume"))
         .arg(Arg::with_name("network_worker")
              .short("n")
              .long("node")
@@ -137,17 +443,62 @@ fn config_from_args() -> Config {
              .help("Render using the specified flux-node process at this address")
              .multiple(true)
              .takes_value(true))
+        .arg(Arg::with_name("node_timeout")
+             .long("node-timeout")
+             .value_name("SECONDS")
+             .help("Give up on a network node that sends nothing for this long while rendering, and requeue its work (defaults to 600; 0 waits forever)")
+             .takes_value(true))
+        .arg(Arg::with_name("secret_file")
+             .long("secret-file")
+             .value_name("FILENAME")
+             .help("Authenticate to network nodes with the shared secret in this file")
+             .takes_value(true))
+        .arg(Arg::with_name("tls_ca")
+             .long("tls-ca")
+             .value_name("FILENAME")
+             .help("Connect to network nodes with TLS, trusting node certificates signed by the PEM certificates in this file")
+             .takes_value(true))
+        .arg(Arg::with_name("result_encoding")
+             .long("result-encoding")
+             .value_name("PRECISION")
+             .help("Precision of the pixels network nodes send back; f32 and f16 use less bandwidth than the full f64 (defaults to f64)")
+             .possible_values(&["f64", "f32", "f16"])
+             .takes_value(true))
+        .arg(Arg::with_name("compress_results")
+             .long("compress-results")
+             .help("Have network nodes compress the pixels they send back"))
+        .arg(Arg::with_name("discover")
+             .long("discover")
+             .help("Also render using the flux-node processes on the local network that answer a discovery probe"))
+        .arg(Arg::with_name("discover_time")
+             .long("discover-time")
+             .value_name("SECONDS")
+             .help("How long to wait for nodes to answer the discovery probe (defaults to 2)")
+             .requires("discover")
+             .takes_value(true))
+        .arg(Arg::with_name("discovery_port")
+             .long("discovery-port")
+             .value_name("PORT")
+             .help("UDP port that nodes answer discovery probes on (defaults to 2001)")
+             .requires("discover")
+             .takes_value(true))
         .arg(Arg::with_name("depth")
              .short("d")
              .long("depth")
              .value_name("DEPTH")
              .help("Tracing depth")
              .takes_value(true))
-        .arg(Arg::with_name("rowsperunit")
-             .short("R")
-             .long("rows")
-             .value_name("COUNT")
-             .help("Image rows per work unit")
+        .arg(Arg::with_name("tile_size")
+             .short("T")
+             .long("tile")
+             .value_name("SIZE")
+             .help("Width and height in pixels of the image tiles used as work units")
+             .takes_value(true))
+        .arg(Arg::with_name("tile_order")
+             .long("tile-order")
+             .value_name("ORDER")
+             .help("Order in which tiles are rendered (defaults to spiral)")
+             .possible_values(&["scanline", "spiral", "hilbert"])
              .takes_value(true))
         .arg(Arg::with_name("skip_local")
              .short("L")
@@ -166,28 +517,151 @@ fn config_from_args() -> Config {
              .short("r")
              .long("root")
              .help("Sample root")
-             .takes_value(true));
+             .takes_value(true))
+        .arg(Arg::with_name("passes")
+             .short("P")
+             .long("passes")
+             .value_name("COUNT")
+             .help("Number of progressive sample passes, each taking sample root squared samples per pixel; 0 renders until stopped (defaults to 1, or 0 with the live preview unless --noise is given)")
+             .takes_value(true))
+        .arg(Arg::with_name("noise")
+             .long("noise")
+             .value_name("THRESHOLD")
+             .help("Enable adaptive sampling: keep sampling each pixel until the relative standard error of its luminance is below this threshold (e.g. 0.02); needs a single pass")
+             .takes_value(true))
+        .arg(Arg::with_name("min_samples")
+             .long("min-samples")
+             .value_name("COUNT")
+             .help("Minimum samples per pixel per pass with adaptive sampling (defaults to sample root squared)")
+             .takes_value(true))
+        .arg(Arg::with_name("max_samples")
+             .long("max-samples")
+             .value_name("COUNT")
+             .help("Maximum samples per pixel per pass with adaptive sampling (defaults to 16 times sample root squared)")
+             .takes_value(true))
+        .arg(Arg::with_name("output")
+             .short("o")
+             .long("output")
+             .value_name("FILE")
+             .help("Write the rendered image to this file (defaults to the scene name with the format's extension)")
+             .takes_value(true))
+        .arg(Arg::with_name("format")
+             .short("f")
+             .long("format")
+             .value_name("FORMAT")
+             .help("Output image format: ppm, png8, png16 or exr (defaults to the output file's extension, or ppm)")
+             .possible_values(&["ppm", "png", "png8", "png16", "exr"])
+             .takes_value(true))
+        .arg(Arg::with_name("tonemap")
+             .long("tonemap")
+             .value_name("OPERATOR")
+             .help("Tone mapping operator for low dynamic range output (overrides the scene)")
+             .possible_values(&["clamp", "reinhard", "aces"])
+             .takes_value(true))
+        .arg(Arg::with_name("exposure")
+             .short("e")
+             .long("exposure")
+             .value_name("STOPS")
+             .help("Exposure adjustment in stops applied before tone mapping (overrides the scene)")
+             .allow_hyphen_values(true)
+             .takes_value(true))
+        .arg(Arg::with_name("checkpoint")
+             .long("checkpoint")
+             .value_name("FILE")
+             .help("Periodically save the render's progress to this file so it can be resumed with --resume")
+             .takes_value(true))
+        .arg(Arg::with_name("checkpoint_interval")
+             .long("checkpoint-interval")
+             .value_name("SECONDS")
+             .help("Seconds between checkpoint writes (defaults to 60)")
+             .takes_value(true))
+        .arg(Arg::with_name("resume")
+             .long("resume")
+             .value_name("FILE")
+             .help("Resume the render saved in this checkpoint file instead of rendering a scene file; the checkpoint is updated as rendering continues unless --checkpoint names another file")
+             .conflicts_with("scene_file")
+             .takes_value(true))
+        .arg(Arg::with_name("partial_interval")
+             .long("partial-interval")
+             .value_name("SECONDS")
+             .help("Write the image rendered so far to the output file this often, with unrendered regions shown as a magenta checkerboard")
+             .takes_value(true))
+        .arg(Arg::with_name("progress")
+             .long("progress")
+             .value_name("FORMAT")
+             .help("Report the render's progress, with per-worker sample rates and an ETA, as text or as JSON lines (not with the live preview; JSON needs --progress-file)")
+             .possible_values(&["text", "json"])
+             .conflicts_with("show_preview")
+             .requires_if("json", "progress_file")
+             .takes_value(true))
+        .arg(Arg::with_name("progress_interval")
+             .long("progress-interval")
+             .value_name("SECONDS")
+             .help("Seconds between progress reports (defaults to 10)")
+             .takes_value(true))
+        .arg(Arg::with_name("progress_file")
+             .long("progress-file")
+             .value_name("FILE")
+             .help("Write progress reports to this file instead of standard output, which also carries the render log")
+             .takes_value(true))
+        .arg(Arg::with_name("linear")
+             .long("linear")
+             .help("Do not apply sRGB encoding to low dynamic range output")
+             .takes_value(false));
 
     let ms = app.get_matches();
-    let default_rows_per_work_unit = 50;
+
+    if let Some(check) = ms.subcommand_matches("check") {
+        check_scene(check.value_of("scene_file").unwrap());
+    }
+    let default_tile_size = 32;
 
     Config {
         show_live_preview: ms.occurrences_of("show_preview") > 0,
-        input_filename: match ms.value_of("scene_file") {
-            None => panic!("Scene filename is required"),
-            Some(f) => String::from(f),
+        input_filename: ms.value_of("scene_file").map(String::from),
+        resume_filename: ms.value_of("resume").map(String::from),
+        checkpoint_filename: ms.value_of("checkpoint").map(String::from),
+        checkpoint_interval: match ms.value_of("checkpoint_interval").map(u64::from_str) {
+            None => Duration::from_secs(DEFAULT_CHECKPOINT_INTERVAL_SECS),
+            Some(Ok(secs)) if secs > 0 => Duration::from_secs(secs),
+            Some(_) => {
+                println!("The checkpoint interval must be a positive number of seconds");
+                exit(1);
+            },
         },
+        partial_interval: ms.value_of("partial_interval").map(|i| Duration::from_secs(u64::from_str(i).unwrap())),
+        progress_format: ms.value_of("progress").map(|f| ProgressFormat::from_str(f).unwrap()),
+        progress_interval: match ms.value_of("progress_interval").map(f64::from_str) {
+            None => Duration::from_secs(DEFAULT_PROGRESS_INTERVAL_SECS),
+            Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+            Some(_) => {
+                println!("The progress interval must be a positive number of seconds");
+                exit(1);
+            },
+        },
+        progress_filename: ms.value_of("progress_file").map(String::from),
         sample_root: match ms.value_of("sample_root") {
             None => DEFAULT_SAMPLE_ROOT,
             Some(r) => usize::from_str(r).unwrap(),
         },
+        passes: ms.value_of("passes").map(|p| usize::from_str(p).unwrap()),
+        noise_threshold: match ms.value_of("noise") {
+            None => 0.0,
+            Some(n) => f64::from_str(n).unwrap(),
+        },
+        min_samples: ms.value_of("min_samples").map(|m| usize::from_str(m).unwrap()),
+        max_samples: ms.value_of("max_samples").map(|m| usize::from_str(m).unwrap()),
         max_depth: match ms.value_of("depth") {
             None => DEFAULT_DEPTH,
             Some(d) => usize::from_str(d).unwrap(),
         },
-        rows_per_work_unit: match ms.value_of("rowsperunit") {
-            None => default_rows_per_work_unit,
-            Some(r) => usize::from_str(r).unwrap(),
+        tile_size: match ms.value_of("tile_size") {
+            None => default_tile_size,
+            Some(t) => usize::from_str(t).unwrap(),
+        },
+        tile_order: match ms.value_of("tile_order") {
+            None => TileOrder::Spiral,
+            Some(o) => TileOrder::from_str(o).unwrap(),
         },
         use_local_worker: match ms.occurrences_of("skip_local") {
             0 => true,
@@ -197,23 +671,49 @@ fn config_from_args() -> Config {
             None => vec![],
             Some(v) => v.map(|s| String::from(s)).collect(),
         },
+        node_timeout: match ms.value_of("node_timeout").map(|t| u64::from_str(t).unwrap()) {
+            None => Some(Duration::from_secs(DEFAULT_NODE_TIMEOUT_SECS)),
+            Some(0) => None,
+            Some(t) => Some(Duration::from_secs(t)),
+        },
+        secret_file: ms.value_of("secret_file").map(String::from),
+        tls_ca: ms.value_of("tls_ca").map(String::from),
+        discover_window: match ms.occurrences_of("discover") {
+            0 => None,
+            _ => {
+                let secs = ms.value_of("discover_time").map_or(DEFAULT_DISCOVER_SECS, |t| f64::from_str(t).unwrap());
+                Some(Duration::from_millis((secs * 1000.0) as u64))
+            },
+        },
+        wire_encoding: WireEncoding {
+            pixels: ms.value_of("result_encoding").map_or(PixelEncoding::F64, |p| PixelEncoding::from_str(p).unwrap()),
+            compress: ms.occurrences_of("compress_results") > 0,
+        },
+        discovery_port: ms.value_of("discovery_port").map_or(DEFAULT_DISCOVERY_PORT, |p| u16::from_str(p).unwrap()),
         num_threads: match ms.value_of("threads") {
             None => num_cpus::get(),
             Some(t) => usize::from_str(t).unwrap(),
         },
+        output_filename: ms.value_of("output").map(String::from),
+        output_format: ms.value_of("format").map(|f| ImageFormat::from_str(f).unwrap()),
+        tone_mapper: ms.value_of("tonemap").map(|t| ToneMapper::from_str(t).unwrap()),
+        exposure: ms.value_of("exposure").map(|e| f64::from_str(e).unwrap()),
+        linear_output: ms.occurrences_of("linear") > 0,
     }
 }
 
-fn title(s: &SceneData, jobcfg: &JobConfiguration) -> String {
+fn title(s: &SceneData, jobcfg: &JobConfiguration, samples_per_pixel: usize) -> String {
     format!("flux render ({}, {} sample{} per pixel, max depth {})",
         s.scene_name,
-        jobcfg.sample_root * jobcfg.sample_root,
-        if jobcfg.sample_root == 1 { "" } else { "s" },
+        samples_per_pixel,
+        if samples_per_pixel == 1 { "" } else { "s" },
         jobcfg.max_trace_depth,
     )
 }
 
-fn show_preview(manager: &mut RenderManager, s: &SceneData, jcfg: JobConfiguration) {
+fn show_preview(manager: &mut RenderManager, s: &SceneData, jobcfg: JobConfiguration,
+                output: &ImageOutput, options: ImageBuilderOptions,
+                remaining: Option<(Job, WorkUnits)>) {
     // SDL setup /////////////////////////////////////////////////////////////
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -221,7 +721,7 @@ fn show_preview(manager: &mut RenderManager, s: &SceneData, jcfg: JobConfigurati
 
     let image_width = s.output_settings.image_width;
     let image_height = s.output_settings.image_height;
-    let window = video_subsystem.window(title(&s, &jcfg).as_str(),
+    let window = video_subsystem.window(title(&s, &jobcfg, 0).as_str(),
                                         image_width as u32,
                                         image_height as u32)
         .position_centered()
@@ -240,45 +740,45 @@ fn show_preview(manager: &mut RenderManager, s: &SceneData, jcfg: JobConfigurati
 
     // Set up GUI ////////////////////////////////////////////////////////////
 
-    let mut copied_rows: Vec<bool> = (0..image_height).map(|_| false).collect();
-    let mut finished = false;
-    let mut jobcfg = jcfg;
-    let mut image_builder = ImageBuilder::new();
-    let mut job = manager.schedule_job(&s, jobcfg, image_builder.sender());
+    // The revision of each image row that was last copied to the
+    // texture; rows are redrawn whenever a pass adds samples to them
+    let mut copied_revisions: Vec<usize> = vec![0; image_height];
+    let mut samples_per_pixel = 0;
+    let image_builder = ImageBuilder::with_options(output.clone(), options);
+    let job = submit_job(manager, s, jobcfg, remaining, image_builder.sender());
 
     'running: loop {
         {
-            if !finished {
-                let img_ref = image_builder.get_image();
-                let mut opt = img_ref.lock().unwrap();
-                match opt.as_mut() {
-                    None => (),
-                    Some(img) => {
-                        let mut num_skipped_rows = 0;
-                        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                            for y in 0..image_height {
-                                if !copied_rows[y] {
-                                    let ps = &img.pixels[y];
-
-                                    if !ps.is_empty() {
-                                        for (x, pixel) in ps.iter().enumerate() {
-                                            let offset = y*pitch + x*3;
-                                            buffer[offset] = (pixel.r * 255.99) as u8;
-                                            buffer[offset + 1] = (pixel.g * 255.99) as u8;
-                                            buffer[offset + 2] = (pixel.b * 255.99) as u8;
-                                        }
-                                        copied_rows[y] = true;
-                                    }
-                                } else {
-                                    num_skipped_rows += 1;
+            let img_ref = image_builder.get_image();
+            let mut opt = img_ref.lock().unwrap();
+            match opt.as_mut() {
+                None => (),
+                Some(img) => {
+                    let mut changed = false;
+                    texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                        for y in 0..image_height {
+                            if copied_revisions[y] != img.row_revisions[y] {
+                                for (x, raw_pixel) in img.pixels[y].iter().enumerate() {
+                                    let pixel = output.tone_mapping.apply(raw_pixel);
+                                    let offset = y*pitch + x*3;
+                                    buffer[offset] = (pixel.r * 255.99) as u8;
+                                    buffer[offset + 1] = (pixel.g * 255.99) as u8;
+                                    buffer[offset + 2] = (pixel.b * 255.99) as u8;
                                 }
+                                copied_revisions[y] = img.row_revisions[y];
+                                changed = true;
                             }
-                        }).unwrap();
-                        if num_skipped_rows == image_height {
-                            finished = true;
                         }
-                    },
-                }
+                    }).unwrap();
+
+                    if changed {
+                        let spp = img.min_sample_count();
+                        if spp != samples_per_pixel {
+                            samples_per_pixel = spp;
+                            canvas.window_mut().set_title(title(&s, &jobcfg, spp).as_str()).unwrap();
+                        }
+                    }
+                },
             }
         }
 
@@ -289,31 +789,14 @@ fn show_preview(manager: &mut RenderManager, s: &SceneData, jcfg: JobConfigurati
             match event {
                 Event::Quit {..} |
                     Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                        // Stop scheduling further passes and wait for
+                        // the outstanding work so that the image written
+                        // out is the accumulation of every sample taken
                         job.cancel();
+                        job.wait();
                         image_builder.stop();
                         break 'running
                     },
-                Event::TextInput { text, .. } => {
-                    if text == "+" {
-                        image_builder.stop();
-                        finished = false;
-                        copied_rows = (0..image_height).map(|_| false).collect();
-                        jobcfg.sample_root += 1;
-                        canvas.window_mut().set_title(title(&s, &jobcfg).as_str()).unwrap();
-                        image_builder = ImageBuilder::new();
-                        job = manager.schedule_job(&s, jobcfg, image_builder.sender());
-                    } else if text == "-" {
-                        if jobcfg.sample_root > 1 {
-                            image_builder.stop();
-                            finished = false;
-                            copied_rows = (0..image_height).map(|_| false).collect();
-                            jobcfg.sample_root -= 1;
-                            canvas.window_mut().set_title(title(&s, &jobcfg).as_str()).unwrap();
-                            image_builder = ImageBuilder::new();
-                            job = manager.schedule_job(&s, jobcfg, image_builder.sender());
-                        }
-                    }
-                },
                 _ => {},
             }
         }
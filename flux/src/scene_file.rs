@@ -0,0 +1,326 @@
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use fluxcore::scene::{SceneData, SceneLibraryData, ShapeData};
+use fluxcore::shapes::MaterialData;
+
+// A problem with a scene file, with its line in the file if known.
+pub struct SceneFileError {
+    pub filename: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.filename, line, self.message),
+            None => write!(f, "{}: {}", self.filename, self.message),
+        }
+    }
+}
+
+// Read a YAML scene file, the scene libraries it includes and any
+// mesh files they refer to, and check the resulting scene for problems.
+// Relative paths are resolved against the directory of the file they
+// appear in. A file that cannot be parsed yields just the parse error;
+// otherwise every problem found is returned.
+pub fn read_scene(filename: &str) -> Result<SceneData, Vec<SceneFileError>> {
+    let mut sources = vec![];
+    let mut s: SceneData = read_file(filename, &mut sources).map_err(|e| vec![e])?;
+    let mut errors = vec![];
+
+    if let Err(e) = s.load_meshes(&directory_of(filename)) {
+        errors.push(sources[0].error(None, format!("could not load mesh: {}", e)));
+    }
+    if let Err(e) = s.load_textures(&directory_of(filename)) {
+        errors.push(sources[0].error(None, format!("could not load texture: {}", e)));
+    }
+
+    // Merge the included libraries, keeping track of where each of the
+    // scene's materials and shapes came from
+    let mut origins = Origins::of_file(0, &s.materials, &s.prototypes, s.shapes.len());
+    let chain = fs::canonicalize(filename).into_iter().collect::<Vec<PathBuf>>();
+    let mut loaded = chain.iter().cloned().collect::<HashSet<PathBuf>>();
+    let includes = std::mem::replace(&mut s.include, vec![]);
+    for (lib, lib_origins) in read_includes(&includes, 0, &chain, &mut loaded, &mut sources, &mut errors) {
+        s.add_library(lib);
+        origins.add(lib_origins);
+    }
+
+    let mut problems: Vec<SceneFileError> = s.validate().into_iter().map(|e| {
+        let (source, path) = origins.locate(&e.path);
+        let source = &sources[source];
+        source.error(source.lines.line_of(&path), format!("{}: {}", path, e.message))
+    }).collect();
+    problems.sort_by(|a, b| (&a.filename, a.line).cmp(&(&b.filename, b.line)));
+    errors.extend(problems);
+
+    if errors.is_empty() {
+        Ok(s)
+    } else {
+        Err(errors)
+    }
+}
+
+// A scene or library file that has been read, for reporting problems
+// with what came from it.
+struct SourceFile {
+    filename: String,
+    lines: LineMap,
+}
+
+impl SourceFile {
+    fn error(&self, line: Option<usize>, message: String) -> SceneFileError {
+        SceneFileError {
+            filename: self.filename.clone(),
+            line,
+            message,
+        }
+    }
+}
+
+// Where the parts of a merged scene or library came from, as indices
+// into the list of source files.
+struct Origins {
+    // The source file of each named table entry, by its path, e.g.
+    // "materials.gold".
+    entries: HashMap<String, usize>,
+    // The source file of each shape and the shape's index there.
+    shapes: Vec<(usize, usize)>,
+}
+
+impl Origins {
+    fn of_file(source: usize, materials: &BTreeMap<String, MaterialData>,
+               prototypes: &BTreeMap<String, ShapeData>, num_shapes: usize) -> Self {
+        let materials = materials.keys().map(|name| format!("materials.{}", name));
+        let prototypes = prototypes.keys().map(|name| format!("prototypes.{}", name));
+
+        Self {
+            entries: materials.chain(prototypes).map(|path| (path, source)).collect(),
+            shapes: (0..num_shapes).map(|i| (source, i)).collect(),
+        }
+    }
+
+    // Track the parts of an included library the same way they are
+    // merged: existing table entries win, and shapes are appended.
+    fn add(&mut self, other: Origins) {
+        for (path, source) in other.entries {
+            self.entries.entry(path).or_insert(source);
+        }
+        self.shapes.extend(other.shapes);
+    }
+
+    // The source file of the value at a path in the merged scene, and
+    // the path of the value in that file.
+    fn locate(&self, path: &str) -> (usize, String) {
+        if path.starts_with("shapes[") {
+            let end = path.find(']').unwrap_or(path.len());
+            if let Some((source, i)) = path[7..end].parse::<usize>().ok().and_then(|n| self.shapes.get(n)) {
+                return (*source, format!("shapes[{}{}", i, &path[end..]));
+            }
+        }
+
+        for (prefix, source) in &self.entries {
+            if path == prefix || path.starts_with(&format!("{}.", prefix)) {
+                return (*source, path.to_string());
+            }
+        }
+
+        (0, path.to_string())
+    }
+}
+
+fn directory_of(filename: &str) -> PathBuf {
+    Path::new(filename).parent().unwrap_or(Path::new(".")).to_path_buf()
+}
+
+// Parse a YAML file, adding it to the list of source files.
+fn read_file<T: DeserializeOwned>(filename: &str, sources: &mut Vec<SourceFile>) -> Result<T, SceneFileError> {
+    let error = |message| SceneFileError {
+        filename: filename.to_string(),
+        line: None,
+        message,
+    };
+
+    let source = fs::read_to_string(filename).map_err(|e| error(format!("{}", e)))?;
+
+    // Parse errors already say where they are
+    let value = serde_yaml::from_str(&source).map_err(|e| error(format!("{}", e)))?;
+
+    sources.push(SourceFile {
+        filename: filename.to_string(),
+        lines: LineMap::new(&source),
+    });
+    Ok(value)
+}
+
+// Read the libraries included by a source file, along with everything
+// they include in turn. The chain holds the files that led to this one,
+// to catch files that include themselves, and loaded holds every file
+// read so far, so a library included along several paths is added once.
+fn read_includes(includes: &[String], from: usize, chain: &[PathBuf], loaded: &mut HashSet<PathBuf>,
+                 sources: &mut Vec<SourceFile>, errors: &mut Vec<SceneFileError>) -> Vec<(SceneLibraryData, Origins)> {
+    let mut libs = vec![];
+
+    for (i, include) in includes.iter().enumerate() {
+        let includer = &sources[from];
+        let line = includer.lines.line_of(&format!("include[{}]", i));
+        let path = directory_of(&includer.filename).join(include);
+
+        let canonical = match fs::canonicalize(&path) {
+            Ok(p) => p,
+            Err(e) => {
+                errors.push(includer.error(line, format!("could not include {}: {}", path.display(), e)));
+                continue;
+            },
+        };
+        if chain.contains(&canonical) {
+            errors.push(includer.error(line, format!("{} is already being included", path.display())));
+            continue;
+        }
+        if !loaded.insert(canonical.clone()) {
+            continue;
+        }
+
+        let filename = path.to_string_lossy().to_string();
+        let mut lib: SceneLibraryData = match read_file(&filename, sources) {
+            Ok(lib) => lib,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            },
+        };
+        let source = sources.len() - 1;
+
+        if let Err(e) = lib.load_meshes(&directory_of(&filename)) {
+            errors.push(sources[source].error(None, format!("could not load mesh: {}", e)));
+        }
+        if let Err(e) = lib.load_textures(&directory_of(&filename)) {
+            errors.push(sources[source].error(None, format!("could not load texture: {}", e)));
+        }
+
+        let mut origins = Origins::of_file(source, &lib.materials, &lib.prototypes, lib.shapes.len());
+        let mut lib_chain = chain.to_vec();
+        lib_chain.push(canonical);
+        let nested = std::mem::replace(&mut lib.include, vec![]);
+        for (nested_lib, nested_origins) in read_includes(&nested, source, &lib_chain, loaded, sources, errors) {
+            lib.add_library(nested_lib);
+            origins.add(nested_origins);
+        }
+
+        libs.push((lib, origins));
+    }
+
+    libs
+}
+
+// The line on which each value in a YAML document starts, by its path
+// as scene validation reports it, e.g. "shapes[2].Sphere.radius". The
+// line of a mapping value is that of its key.
+struct LineMap {
+    lines: HashMap<String, usize>,
+    // The collections enclosing the current position in the document.
+    stack: Vec<Collection>,
+}
+
+enum Collection {
+    // The key is that of the value expected next, or None if a key is
+    // expected next.
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, next_index: usize },
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+impl LineMap {
+    fn new(source: &str) -> Self {
+        let mut map = Self {
+            lines: HashMap::new(),
+            stack: vec![],
+        };
+
+        // The source has already been parsed successfully, but if this
+        // fails anyway, the lines found so far are still useful
+        Parser::new(source.chars()).load(&mut map, false).ok();
+        map
+    }
+
+    // The line of the value at the path, or of the closest enclosing
+    // value that is in the document.
+    fn line_of(&self, path: &str) -> Option<usize> {
+        let mut path = path;
+        loop {
+            if let Some(line) = self.lines.get(path) {
+                return Some(*line);
+            }
+            match path.rfind(|c| c == '.' || c == '[') {
+                Some(i) => path = &path[..i],
+                None => return None,
+            }
+        }
+    }
+
+    // Note a node starting on the given line, returning its path. A
+    // mapping key gets the path of the value that follows it.
+    fn start_node(&mut self, ev: &Event, line: usize) -> String {
+        let path = match self.stack.last_mut() {
+            None => String::new(),
+            Some(Collection::Sequence { path, next_index }) => {
+                let p = format!("{}[{}]", path, next_index);
+                *next_index += 1;
+                p
+            },
+            Some(Collection::Mapping { path, key }) => match key.take() {
+                Some(k) => join(path, &k),
+                None => {
+                    let k = match ev {
+                        Event::Scalar(k, ..) => k.clone(),
+                        _ => String::new(),
+                    };
+                    let p = join(path, &k);
+                    *key = Some(k);
+                    p
+                },
+            },
+        };
+
+        self.lines.entry(path.clone()).or_insert(line);
+        path
+    }
+}
+
+impl MarkedEventReceiver for LineMap {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::Scalar(..) | Event::Alias(_) => {
+                self.start_node(&ev, mark.line());
+            },
+            Event::SequenceStart(_) => {
+                let path = self.start_node(&ev, mark.line());
+                self.stack.push(Collection::Sequence { path, next_index: 0 });
+            },
+            Event::MappingStart(_) => {
+                let path = self.start_node(&ev, mark.line());
+                self.stack.push(Collection::Mapping { path, key: None });
+            },
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.stack.pop();
+            },
+            _ => (),
+        }
+    }
+}
@@ -1,19 +1,33 @@
 
 use nalgebra::{Vector3};
 
-use crate::samplers::{to_unit_hemi, UnitSquareSample};
+use crate::samplers::UnitSquareSample;
 use crate::common::Hit;
 use crate::color::Color;
 use crate::constants::INV_PI;
+use crate::texture::Texture;
 
 pub trait BRDF: Send + Sync {
     fn sample_f(&self, hit: &Hit, wo: &Vector3<f64>,
                 hemi_sample: &Vector3<f64>, square_sample: &UnitSquareSample) -> (Vector3<f64>, f64, Color);
+
+    // Evaluate the BRDF for a given pair of directions, e.g. toward a
+    // sampled point on a light.
+    fn f(&self, hit: &Hit, wo: &Vector3<f64>, wi: &Vector3<f64>) -> Color;
+
+    // The density with which sample_f would choose wi.
+    fn pdf(&self, hit: &Hit, wo: &Vector3<f64>, wi: &Vector3<f64>) -> f64;
+
+    // Whether this BRDF reflects in a single direction, in which case
+    // f() and pdf() are always zero and light sampling is pointless.
+    fn is_delta(&self) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian {
     pub diffuse_coefficient: f64,
-    pub diffuse_color: Color,
+    pub diffuse_color: Box<dyn Texture>,
 }
 
 impl BRDF for Lambertian {
@@ -26,13 +40,22 @@ impl BRDF for Lambertian {
         let wi = (hemi_sample.x * u + hemi_sample.y * v + hemi_sample.z * w).normalize();
         let pdf = hit.normal.dot(&wi) * INV_PI;
 
-        (wi, pdf, self.diffuse_color * self.diffuse_coefficient * INV_PI)
+        (wi, pdf, self.diffuse_color.at_hit(hit) * self.diffuse_coefficient * INV_PI)
+    }
+
+    fn f(&self, hit: &Hit, _wo: &Vector3<f64>, _wi: &Vector3<f64>) -> Color {
+        self.diffuse_color.at_hit(hit) * self.diffuse_coefficient * INV_PI
+    }
+
+    fn pdf(&self, hit: &Hit, _wo: &Vector3<f64>, wi: &Vector3<f64>) -> f64 {
+        let ndotwi = hit.normal.dot(&wi);
+        if ndotwi > 0.0 { ndotwi * INV_PI } else { 0.0 }
     }
 }
 
 pub struct PerfectSpecular {
     pub kr: f64,
-    pub cr: Color,
+    pub cr: Box<dyn Texture>,
 }
 
 impl BRDF for PerfectSpecular {
@@ -41,39 +64,279 @@ impl BRDF for PerfectSpecular {
         let ndotwo = hit.normal.dot(&wo);
         let wi = -wo + hit.normal * ndotwo * 2.0;
         let pdf = hit.normal.dot(&wi);
-        (wi, pdf, self.cr * self.kr)
+        (wi, pdf, self.cr.at_hit(hit) * self.kr)
+    }
+
+    fn f(&self, _hit: &Hit, _wo: &Vector3<f64>, _wi: &Vector3<f64>) -> Color {
+        Color::black()
     }
+
+    fn pdf(&self, _hit: &Hit, _wo: &Vector3<f64>, _wi: &Vector3<f64>) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+// The complex index of refraction of a conductor such as a metal, per
+// color channel.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct ComplexIor {
+    pub eta: Color,
+    pub k: Color,
+}
+
+// The smallest GGX alpha used, since a perfectly smooth surface has a
+// distribution of normals that cannot be evaluated.
+const MIN_ALPHA: f64 = 1e-3;
+
+// The GGX alpha for a perceptual roughness in [0, 1], as most PBR tools
+// define it.
+pub fn alpha_from_roughness(roughness: f64) -> f64 {
+    (roughness * roughness).max(MIN_ALPHA)
 }
 
-pub struct GlossySpecular {
+// The GGX alpha whose highlights most resemble those of a Phong lobe
+// with the given exponent.
+pub fn alpha_from_phong_exponent(exp: f64) -> f64 {
+    (2.0 / (exp + 2.0)).sqrt().max(MIN_ALPHA)
+}
+
+// A metallic/roughness BRDF built on the GGX microfacet distribution.
+// The surface is a blend, by the metallic fraction, of a metal and a
+// dielectric. The metal reflects specularly with the base color as its
+// reflectance at normal incidence, or with the reflectance of a
+// conductor tinted by the base color. The dielectric is a diffuse base
+// of the base color under a clear specular coat with index of
+// refraction ior.
+//
+// Directions are sampled either from the cosine-weighted hemisphere or
+// from the GGX distribution of normals visible from wo; pdf() is the
+// density of the combination.
+pub struct Microfacet {
     pub ks: f64,
-    pub cs: Color,
-    pub exp: f64,
+    pub base_color: Box<dyn Texture>,
+    pub metallic: f64,
+    pub alpha: f64,
+    pub ior: f64,
+    pub conductor: Option<ComplexIor>,
 }
 
-impl BRDF for GlossySpecular {
+impl Microfacet {
+    // A basis with w along the normal.
+    fn basis(&self, hit: &Hit) -> (Vector3<f64>, Vector3<f64>, Vector3<f64>) {
+        let w = hit.normal;
+        let v = Vector3::new(0.0034, 1.0, 0.0071).cross(&w).normalize();
+        let u = v.cross(&w);
+        (u, v, w)
+    }
+
+    // The chance of sampling the specular lobe rather than the diffuse
+    // one. Metals have no diffuse lobe.
+    fn specular_probability(&self) -> f64 {
+        0.5 * (1.0 + self.metallic)
+    }
+
+    // The reflectance of the non-metal's coat at normal incidence.
+    fn coat_f0(&self) -> f64 {
+        ((self.ior - 1.0) / (self.ior + 1.0)).powi(2)
+    }
+
+    // The GGX distribution of normals, given the cosine of the angle
+    // between a normal and the surface normal.
+    fn distribution(&self, cos_h: f64) -> f64 {
+        let a2 = self.alpha * self.alpha;
+        let d = cos_h * cos_h * (a2 - 1.0) + 1.0;
+        a2 * INV_PI / (d * d)
+    }
+
+    // Smith's auxiliary function for a direction at the given cosine to
+    // the normal, from which the masking terms are built.
+    fn lambda(&self, cos: f64) -> f64 {
+        let cos2 = cos * cos;
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    // Choose a normal from the distribution of those visible from wo, in
+    // a space where the surface normal is z, by Heitz's method of
+    // sampling a projected hemisphere of the stretched distribution.
+    fn sample_visible_normal(&self, wo: &Vector3<f64>, sample: &UnitSquareSample) -> Vector3<f64> {
+        let vh = Vector3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalize();
+
+        let len2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len2 > 0.0 {
+            Vector3::new(-vh.y, vh.x, 0.0) / len2.sqrt()
+        } else {
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+
+        let r = sample.x.sqrt();
+        let phi = 2.0 * std::f64::consts::PI * sample.y;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+        Vector3::new(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(0.0)).normalize()
+    }
+}
+
+impl BRDF for Microfacet {
     fn sample_f(&self, hit: &Hit, wo: &Vector3<f64>,
-                _hemi_sample: &Vector3<f64>, pixel_sample: &UnitSquareSample) -> (Vector3<f64>, f64, Color) {
-        let ndotwo = hit.normal.dot(&wo);
-        let r = -wo + hit.normal * ndotwo * 2.0;
+                hemi_sample: &Vector3<f64>, square_sample: &UnitSquareSample) -> (Vector3<f64>, f64, Color) {
+        let (u, v, w) = self.basis(hit);
+
+        // Use the first dimension of the square sample to choose a lobe,
+        // then rescale it to [0, 1) to choose a normal if it is the
+        // specular one.
+        let p = self.specular_probability();
+        let wi = if square_sample.x < p {
+            let local_wo = Vector3::new(wo.dot(&u), wo.dot(&v), wo.dot(&w));
+            if local_wo.z <= 0.0 {
+                return (w, 0.0, Color::black());
+            }
+            let normal_sample = UnitSquareSample {
+                x: square_sample.x / p,
+                y: square_sample.y,
+            };
+            let m = self.sample_visible_normal(&local_wo, &normal_sample);
+            let m = u * m.x + v * m.y + w * m.z;
+            -wo + m * wo.dot(&m) * 2.0
+        } else {
+            (hemi_sample.x * u + hemi_sample.y * v + hemi_sample.z * w).normalize()
+        };
+
+        (wi, self.pdf(hit, wo, &wi), self.f(hit, wo, &wi))
+    }
 
-        let w = r;
-        let u = Vector3::new(0.00424, 1.0, 0.00764).cross(&w).normalize();
-        let v = u.cross(&w);
+    fn f(&self, hit: &Hit, wo: &Vector3<f64>, wi: &Vector3<f64>) -> Color {
+        let cos_o = hit.normal.dot(wo);
+        let cos_i = hit.normal.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Color::black();
+        }
 
-        let hemi_sample = to_unit_hemi(&pixel_sample, self.exp);
-        let wi0 = u * hemi_sample.x + v * hemi_sample.y + w * hemi_sample.z;
+        let h = (wo + wi).normalize();
+        let cos_d = wi.dot(&h);
+        let specular = self.distribution(hit.normal.dot(&h)) /
+            ((1.0 + self.lambda(cos_o) + self.lambda(cos_i)) * 4.0 * cos_o * cos_i);
+
+        let base = self.base_color.at_hit(hit);
+        let metal = match &self.conductor {
+            Some(c) => fresnel_conductor(cos_d, c) * base,
+            None => fresnel_schlick(cos_d, base),
+        };
+
+        // Light reaching the diffuse base passes through the coat on the
+        // way in and again on the way out.
+        let coat = |cos: f64| fresnel_schlick(cos, Color::all(self.coat_f0())).r;
+        let diffuse = (1.0 - coat(cos_o)) * (1.0 - coat(cos_i)) * INV_PI;
+        let dielectric = base * diffuse + Color::all(coat(cos_d) * specular);
+
+        (metal * (specular * self.metallic) + dielectric * (1.0 - self.metallic)) * self.ks
+    }
+
+    fn pdf(&self, hit: &Hit, wo: &Vector3<f64>, wi: &Vector3<f64>) -> f64 {
+        let cos_o = hit.normal.dot(wo);
+        let cos_i = hit.normal.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return 0.0;
+        }
+
+        // The density of visible normals is G1(wo) D(h) (wo . h) / cos_o,
+        // and reflecting about h scales it by 1 / 4 (wo . h).
+        let h = (wo + wi).normalize();
+        let specular = self.distribution(hit.normal.dot(&h)) / ((1.0 + self.lambda(cos_o)) * 4.0 * cos_o);
+        let p = self.specular_probability();
+
+        p * specular + (1.0 - p) * cos_i * INV_PI
+    }
+}
+
+// Schlick's approximation of the Fresnel reflectance at the given cosine
+// of the angle of incidence, from the reflectance at normal incidence.
+pub fn fresnel_schlick(cos_i: f64, f0: Color) -> Color {
+    let x = (1.0 - cos_i).max(0.0).powi(5);
+    f0 * (1.0 - x) + Color::all(x)
+}
+
+// The fraction of unpolarized light reflected by a conductor, per color
+// channel, given the cosine of the angle of incidence.
+pub fn fresnel_conductor(cos_i: f64, ior: &ComplexIor) -> Color {
+    let channel = |eta: f64, k: f64| {
+        let cos2 = cos_i * cos_i;
+        let sin2 = 1.0 - cos2;
+        let t0 = eta * eta - k * k - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2.0 * cos_i * a;
+        let r_perpendicular = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let r_parallel = r_perpendicular * (t3 - t4) / (t3 + t4);
+
+        0.5 * (r_parallel + r_perpendicular)
+    };
+
+    Color::new(channel(ior.eta.r, ior.k.r), channel(ior.eta.g, ior.k.g), channel(ior.eta.b, ior.k.b))
+}
+
+// The fraction of unpolarized light reflected at a smooth boundary
+// between media with indices of refraction eta_i (the side the light
+// arrives from) and eta_t, given the cosine of the angle of incidence.
+// Returns 1.0 in the case of total internal reflection.
+pub fn fresnel_dielectric(cos_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin_t * sin_t).sqrt();
+    let r_parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let r_perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+pub trait BTDF: Send + Sync {
+    // Choose the direction in which light arriving from wo is
+    // transmitted and the fraction transmitted, or return None if wo is
+    // totally internally reflected.
+    fn sample_f(&self, hit: &Hit, wo: &Vector3<f64>) -> Option<(Vector3<f64>, Color)>;
+}
+
+// Transmission through a smooth boundary, e.g. into or out of glass. The
+// hit normal is taken to point out of the object, so a ray arriving
+// from the back side of the surface is leaving the object.
+pub struct PerfectTransmitter {
+    pub kt: f64,
+    pub ior: f64,
+}
 
-        let wi = if hit.normal.dot(&wi0) < 0.0 {
-            u * -hemi_sample.x - v * hemi_sample.y + w * hemi_sample.z
+impl BTDF for PerfectTransmitter {
+    fn sample_f(&self, hit: &Hit, wo: &Vector3<f64>) -> Option<(Vector3<f64>, Color)> {
+        let cos_wo = hit.normal.dot(&wo);
+        let (n, eta, cos_i) = if cos_wo > 0.0 {
+            (hit.normal, 1.0 / self.ior, cos_wo)
         } else {
-            wi0
+            (-hit.normal, self.ior, -cos_wo)
         };
 
-        let phong_lobe = r.dot(&wi).powf(self.exp);
-        let pdf = phong_lobe * hit.normal.dot(&wi);
-        let color = self.cs * self.ks * phong_lobe;
+        let cos_t_squared = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+        if cos_t_squared < 0.0 {
+            return None;
+        }
 
-        (wi, pdf, color)
+        let wt = -wo * eta + n * (eta * cos_i - cos_t_squared.sqrt());
+        Some((wt.normalize(), Color::all(self.kt)))
     }
 }
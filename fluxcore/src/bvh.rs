@@ -0,0 +1,206 @@
+
+use crate::common::Ray;
+use crate::shapes::BoundingBox;
+
+// Cost of visiting an interior node relative to intersecting one item,
+// used by the surface area heuristic.
+const TRAVERSAL_COST: f64 = 1.0;
+const MAX_LEAF_SIZE: usize = 4;
+// Traversal keeps at most one node per level of the tree waiting on its
+// stack, so the depth of the tree is limited to fit a fixed-size stack.
+const MAX_DEPTH: usize = 63;
+const STACK_SIZE: usize = MAX_DEPTH + 1;
+
+pub trait Bounded {
+    fn bounds(&self) -> BoundingBox;
+}
+
+enum BvhNode {
+    Leaf { bbox: BoundingBox, start: usize, count: usize },
+    Interior { bbox: BoundingBox, left: usize, right: usize },
+}
+
+impl BvhNode {
+    fn bbox(&self) -> &BoundingBox {
+        match self {
+            BvhNode::Leaf { bbox, .. } => bbox,
+            BvhNode::Interior { bbox, .. } => bbox,
+        }
+    }
+}
+
+// A bounding volume hierarchy built with the surface area heuristic.
+// The nodes are stored in a flat vector with the root at index zero,
+// and the items are reordered so that each leaf refers to a contiguous
+// range of them.
+pub struct Bvh<T> {
+    items: Vec<T>,
+    nodes: Vec<BvhNode>,
+}
+
+struct BuildItem {
+    index: usize,
+    bbox: BoundingBox,
+    centroid: [f64; 3],
+}
+
+impl<T: Bounded> Bvh<T> {
+    pub fn new(items: Vec<T>) -> Self {
+        let mut build_items: Vec<BuildItem> = items.iter().enumerate().map(|(index, item)| {
+            let bbox = item.bounds();
+            let c = bbox.centroid();
+            BuildItem { index, bbox, centroid: [c.x, c.y, c.z] }
+        }).collect();
+
+        let mut nodes = vec![];
+        if !build_items.is_empty() {
+            build(&mut build_items, 0, 0, &mut nodes);
+        }
+
+        // Reorder the items to match the order of the build items, which
+        // the leaves index into.
+        let mut slots: Vec<Option<T>> = items.into_iter().map(Some).collect();
+        let items = build_items.iter().map(|b| slots[b.index].take().unwrap()).collect();
+
+        Self { items, nodes }
+    }
+}
+
+impl<T> Bvh<T> {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        self.nodes.first().map(|n| *n.bbox())
+    }
+
+    // Find the closest item hit by the ray. The hit function returns the
+    // distance along the ray to the intersection along with any other
+    // data the caller needs.
+    pub fn hit<'a, R, F>(&'a self, r: &Ray, hit_item: F) -> Option<(f64, R)>
+        where F: Fn(&'a T) -> Option<(f64, R)>
+    {
+        let mut closest: Option<(f64, R)> = None;
+
+        // Nodes waiting to be visited, with the distance at which the ray
+        // enters their bounds.
+        let mut stack = [(0usize, 0.0f64); STACK_SIZE];
+        let mut len = 0;
+
+        if let Some(t) = self.nodes.first().and_then(|n| n.bbox().hit_distance(r)) {
+            stack[0] = (0, t);
+            len = 1;
+        }
+
+        while len > 0 {
+            len -= 1;
+            let (i, t) = stack[len];
+            let node = &self.nodes[i];
+
+            // A closer hit may have been found since the node was pushed
+            if t > closest.as_ref().map_or(std::f64::INFINITY, |c| c.0) {
+                continue;
+            }
+
+            match node {
+                BvhNode::Leaf { start, count, .. } => {
+                    for item in &self.items[*start..*start + *count] {
+                        if let Some(h) = hit_item(item) {
+                            if h.0 < closest.as_ref().map_or(std::f64::INFINITY, |c| c.0) {
+                                closest = Some(h);
+                            }
+                        }
+                    }
+                },
+                BvhNode::Interior { left, right, .. } => {
+                    // Push the farther child first so that the nearer one
+                    // is visited first and can rule the farther one out
+                    let lt = self.nodes[*left].bbox().hit_distance(r);
+                    let rt = self.nodes[*right].bbox().hit_distance(r);
+                    let (near, far) = match (lt, rt) {
+                        (Some(tl), Some(tr)) if tr < tl => ((*right, tr), Some((*left, tl))),
+                        (Some(tl), Some(tr)) => ((*left, tl), Some((*right, tr))),
+                        (Some(tl), None) => ((*left, tl), None),
+                        (None, Some(tr)) => ((*right, tr), None),
+                        (None, None) => continue,
+                    };
+
+                    if let Some(f) = far {
+                        stack[len] = f;
+                        len += 1;
+                    }
+                    stack[len] = near;
+                    len += 1;
+                },
+            }
+        }
+
+        closest
+    }
+}
+
+fn union_all(items: &[BuildItem]) -> BoundingBox {
+    let mut b = items[0].bbox;
+    for i in &items[1..] {
+        b = b.union(&i.bbox);
+    }
+    b
+}
+
+// Build the subtree for the given items at the given depth, returning
+// the index of its root node.
+fn build(items: &mut [BuildItem], offset: usize, depth: usize, nodes: &mut Vec<BvhNode>) -> usize {
+    let bbox = union_all(items);
+    let node_index = nodes.len();
+    nodes.push(BvhNode::Leaf { bbox, start: offset, count: items.len() });
+
+    if items.len() <= MAX_LEAF_SIZE || depth >= MAX_DEPTH {
+        return node_index;
+    }
+
+    let parent_area = bbox.surface_area();
+    let n = items.len();
+    let mut best: Option<(f64, usize, usize)> = None;
+
+    for axis in 0..3 {
+        items.sort_by(|a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+
+        // right_areas[i] is the surface area of the bounds of items[i..].
+        let mut right_areas = vec![0.0; n];
+        let mut right = items[n - 1].bbox;
+        for i in (1..n).rev() {
+            right = right.union(&items[i].bbox);
+            right_areas[i] = right.surface_area();
+        }
+
+        let mut left = items[0].bbox;
+        for i in 1..n {
+            let cost = TRAVERSAL_COST +
+                (left.surface_area() * i as f64 + right_areas[i] * (n - i) as f64) / parent_area;
+
+            if best.map_or(true, |(c, _, _)| cost < c) {
+                best = Some((cost, axis, i));
+            }
+
+            left = left.union(&items[i].bbox);
+        }
+    }
+
+    let (cost, axis, split) = best.unwrap();
+
+    // Keep this node as a leaf if splitting does not pay for itself.
+    // Degenerate bounds (e.g. all items sharing one centroid) produce a
+    // NaN cost, which also ends up here.
+    if cost >= n as f64 || cost.is_nan() {
+        return node_index;
+    }
+
+    items.sort_by(|a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+    let (left_items, right_items) = items.split_at_mut(split);
+    let left = build(left_items, offset, depth + 1, nodes);
+    let right = build(right_items, offset + split, depth + 1, nodes);
+    nodes[node_index] = BvhNode::Interior { bbox, left, right };
+
+    node_index
+}
@@ -0,0 +1,283 @@
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::time::{Duration, Instant};
+
+use crate::color::Color;
+use crate::image::{Image, ImageOutput};
+use crate::job::{Job, JobConfiguration, JobID, WorkUnit};
+use crate::manager::WorkUnits;
+use crate::protocol::FLUX_VERSION;
+use crate::scene::SceneData;
+use crate::debug::d_println;
+
+// Bump this whenever the checkpoint format changes. Version 3 stores
+// shape materials as references into the scene's materials table, and
+// version 4 counts finished passes instead of listing their units.
+const CHECKPOINT_VERSION: u32 = 4;
+
+// Everything needed to resume a job: the job itself, where its image
+// goes, which work units have been rendered and the image they add up
+// to.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub flux_version: String,
+    pub job: Job,
+    pub output: ImageOutput,
+    // Passes before this one are fully rendered, and completed holds
+    // the units rendered from the passes after them.
+    pub passes_done: usize,
+    pub completed: Vec<WorkUnit>,
+    pub pixels: Vec<Vec<Color>>,
+    pub sample_counts: Vec<Vec<usize>>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Checkpoint {
+    pub fn read(path: &str) -> io::Result<Self> {
+        let f = BufReader::new(File::open(path)?);
+        let checkpoint: Checkpoint = serde_cbor::from_reader(f)
+            .map_err(|e| invalid(format!("{} is not a valid checkpoint: {}", path, e)))?;
+
+        if checkpoint.version != CHECKPOINT_VERSION {
+            return Err(invalid(format!("{} is a version {} checkpoint written by flux {}, but this flux reads version {}",
+                                       path, checkpoint.version, checkpoint.flux_version, CHECKPOINT_VERSION)));
+        }
+
+        Ok(checkpoint)
+    }
+
+    // Write the checkpoint next to its final location first, so that a
+    // crash while writing leaves the previous checkpoint intact.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let mut f = BufWriter::new(File::create(&tmp_path)?);
+            serde_cbor::to_writer(&mut f, self).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    // The image rendered so far.
+    pub fn image(&self) -> Image {
+        let height = self.job.scene_data.output_settings.image_height;
+        let width = self.job.scene_data.output_settings.image_width;
+
+        let mut img = Image::new(width, height);
+        img.pixels = self.pixels.clone();
+        img.sample_counts = self.sample_counts.clone();
+        img.row_revisions = vec![1; height];
+        img
+    }
+
+    // The job's work units that have not been rendered, in the order
+    // the job would have handed them out. A tile that was split near
+    // the end of a pass and only partly rendered yields the pieces that
+    // are missing.
+    pub fn remaining_work_units(&self) -> WorkUnits {
+        let tile_size = self.job.config.tile_size;
+        let mut done: HashMap<(usize, usize, usize), Vec<WorkUnit>> = HashMap::new();
+        for u in &self.completed {
+            done.entry(tile_key(u, tile_size)).or_insert_with(Vec::new).push(*u);
+        }
+
+        Box::new(self.job.work_units_from(self.passes_done).flat_map(move |u| {
+            let tile_done = done.get(&tile_key(&u, tile_size)).map_or(&[][..], |us| us.as_slice());
+            missing_parts(u, tile_done)
+        }))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining_work_units().next().is_none()
+    }
+
+    // The number of pixels and work units rendered so far, counting
+    // each finished pass as the units it is made of.
+    pub fn pixels_done(&self) -> usize {
+        let settings = &self.job.scene_data.output_settings;
+        self.passes_done * settings.image_width * settings.image_height +
+            self.completed.iter().map(|u| u.width() * u.height()).sum::<usize>()
+    }
+
+    pub fn units_done(&self) -> usize {
+        self.passes_done * self.job.pass_work_units(0).len() + self.completed.len()
+    }
+
+    // Add a rendered unit, and replace the units of any passes that are
+    // now fully rendered with a count, so that the checkpoint does not
+    // grow with the number of passes.
+    fn add_completed(&mut self, unit: WorkUnit) {
+        self.completed.push(unit);
+
+        let settings = &self.job.scene_data.output_settings;
+        let pixels_per_pass = settings.image_width * settings.image_height;
+        loop {
+            let pass = self.passes_done;
+            let pass_pixels: usize = self.completed.iter()
+                .filter(|u| u.pass == pass)
+                .map(|u| u.width() * u.height())
+                .sum();
+            if pass_pixels < pixels_per_pass {
+                break;
+            }
+
+            self.completed.retain(|u| u.pass != pass);
+            self.passes_done += 1;
+        }
+    }
+}
+
+// Work units only ever cover part of one tile of one pass.
+fn tile_key(unit: &WorkUnit, tile_size: usize) -> (usize, usize, usize) {
+    (unit.pass, unit.row_start / tile_size, unit.col_start / tile_size)
+}
+
+fn overlaps(a: &WorkUnit, b: &WorkUnit) -> bool {
+    a.row_start <= b.row_end && b.row_start <= a.row_end &&
+        a.col_start <= b.col_end && b.col_start <= a.col_end
+}
+
+// The parts of a unit not covered by the done units, found by splitting
+// it the same way the work queue does.
+fn missing_parts(unit: WorkUnit, done: &[WorkUnit]) -> Vec<WorkUnit> {
+    if done.contains(&unit) {
+        return vec![];
+    }
+    if !done.iter().any(|d| overlaps(d, &unit)) {
+        return vec![unit];
+    }
+
+    match unit.split() {
+        Some((first, second)) => {
+            let mut parts = missing_parts(first, done);
+            parts.extend(missing_parts(second, done));
+            parts
+        },
+        None => {
+            // This cannot happen with units split by the work queue;
+            // render the whole unit again rather than leave a hole
+            d_println(format!("Checkpoint: unit {:?} is only partly rendered", unit));
+            vec![unit]
+        },
+    }
+}
+
+// Writes checkpoints of a job as its results arrive.
+pub struct Checkpointer {
+    path: String,
+    interval: Duration,
+    // The job's scene, configuration and output, until the job starts
+    // and has an ID.
+    new_job: Option<(SceneData, JobConfiguration, ImageOutput)>,
+    checkpoint: Option<Checkpoint>,
+    resumed: bool,
+    last_write: Instant,
+}
+
+impl Checkpointer {
+    pub fn new(path: String, interval: Duration, scene_data: &SceneData,
+               config: JobConfiguration, output: ImageOutput) -> Self {
+        Self {
+            path,
+            interval,
+            new_job: Some((scene_data.clone(), config, output)),
+            checkpoint: None,
+            resumed: false,
+            last_write: Instant::now(),
+        }
+    }
+
+    // Carry on checkpointing a job that is being resumed from a
+    // checkpoint.
+    pub fn resume(path: String, interval: Duration, checkpoint: Checkpoint) -> Self {
+        Self {
+            path,
+            interval,
+            new_job: None,
+            checkpoint: Some(checkpoint),
+            resumed: true,
+            last_write: Instant::now(),
+        }
+    }
+
+    // The image to continue from, if the job is being resumed.
+    pub fn initial_image(&self) -> Option<Image> {
+        match (&self.checkpoint, self.resumed) {
+            (Some(c), true) => Some(c.image()),
+            _ => None,
+        }
+    }
+
+    pub fn job_started(&mut self, job_id: JobID) {
+        if let Some((scene_data, config, output)) = self.new_job.take() {
+            self.checkpoint = Some(Checkpoint {
+                version: CHECKPOINT_VERSION,
+                flux_version: FLUX_VERSION.to_string(),
+                job: Job {
+                    id: job_id,
+                    scene_data,
+                    config,
+                },
+                output,
+                passes_done: 0,
+                completed: vec![],
+                pixels: vec![],
+                sample_counts: vec![],
+            });
+        }
+    }
+
+    // Record a unit whose result has been added to the image, writing a
+    // checkpoint if one is due.
+    pub fn unit_completed(&mut self, unit: WorkUnit, img: &Image) {
+        let checkpoint = match self.checkpoint.as_mut() {
+            Some(c) => c,
+            None => panic!("Checkpointer::unit_completed: job has not started"),
+        };
+        checkpoint.add_completed(unit);
+
+        if self.last_write.elapsed() >= self.interval {
+            self.write(img);
+        }
+    }
+
+    // When the job ends, keep a checkpoint only if there is work left;
+    // the job may have been cancelled or run out of workers.
+    pub fn job_finished(&mut self, img: &Image) {
+        let complete = match &self.checkpoint {
+            Some(c) => c.is_complete(),
+            None => return,
+        };
+
+        if complete {
+            match fs::remove_file(&self.path) {
+                Ok(()) => println!("Render complete, removed checkpoint {}", self.path),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => println!("Could not remove checkpoint {}: {}", self.path, e),
+            }
+        } else {
+            self.write(img);
+        }
+    }
+
+    fn write(&mut self, img: &Image) {
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.pixels = img.pixels.clone();
+            checkpoint.sample_counts = img.sample_counts.clone();
+
+            match checkpoint.write(&self.path) {
+                Ok(()) => println!("Wrote checkpoint {} ({} work units done)", self.path, checkpoint.units_done()),
+                Err(e) => println!("Could not write checkpoint {}: {}", self.path, e),
+            }
+        }
+
+        self.last_write = Instant::now();
+    }
+}
@@ -32,6 +32,15 @@ impl Color {
         Color::all(1.0)
     }
 
+    pub fn powf(&self, e: f64) -> Color {
+        Color::new(self.r.powf(e), self.g.powf(e), self.b.powf(e))
+    }
+
+    // Relative luminance of a linear sRGB color.
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     pub fn max_to_one(&mut self) -> () {
         let mx1 = if self.r > self.g { self.r } else { self.g };
         let mx2 = if mx1 > self.b { mx1 } else { self.b };
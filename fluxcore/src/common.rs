@@ -1,12 +1,16 @@
 
 use std::cmp::Ordering;
-use nalgebra::{Vector3, Point3};
+use nalgebra::{Vector3, Point2, Point3};
 
 use crate::materials::Material;
+use crate::shapes::BoundingBox;
+use crate::samplers::UnitSquareSample;
 
 pub struct Hit<'a> {
     pub local_hit_point: Point3<f64>,
     pub normal: Vector3<f64>,
+    // The surface's texture coordinates at the hit point.
+    pub uv: Point2<f64>,
     pub material: &'a dyn Material,
     pub distance: f64,
     pub ray: Ray,
@@ -30,5 +34,20 @@ pub struct Ray {
 }
 
 pub trait Intersectable: Sync + Send {
+    // The world-space bounds of this shape, or None if it is unbounded
+    // (like a plane) and so cannot be placed in a bounding volume
+    // hierarchy.
+    fn bounding_box(&self) -> Option<BoundingBox>;
     fn hit<'a>(&'a self, r: &Ray, depth: usize) -> Option<Hit<'a>>;
 }
+
+// A shape whose surface can be sampled uniformly, e.g. so that it can be
+// used as an area light.
+pub trait Sampleable: Sync + Send {
+    fn sample(&self, s: &UnitSquareSample) -> Point3<f64>;
+    fn normal_at(&self, p: &Point3<f64>) -> Vector3<f64>;
+    fn uv_at(&self, p: &Point3<f64>) -> Point2<f64>;
+
+    // The probability density of sample() with respect to surface area.
+    fn pdf(&self) -> f64;
+}
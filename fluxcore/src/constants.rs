@@ -2,5 +2,13 @@
 use std::f64;
 
 pub const T_MIN: f64 = 0.0005;
+// Fraction of the distance to a sampled light point within which a
+// shadow ray hit is considered to be the light itself.
+pub const SHADOW_EPSILON: f64 = 0.0001;
+// Pixels darker than this have their adaptive sampling error measured
+// relative to this luminance instead, so that nearly black pixels do
+// not demand an unbounded number of samples.
+pub const MIN_ADAPTIVE_LUMINANCE: f64 = 0.01;
 pub const INV_PI: f64 = 1.0 / std::f64::consts::PI;
 pub const DEFAULT_PORT: &str = "2000";
+pub const DEFAULT_DISCOVERY_PORT: u16 = 2001;
@@ -0,0 +1,131 @@
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::manager::WorkerInfo;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::debug::d_println;
+
+// Managers look for nodes by sending a probe to this multicast group,
+// and every node listening on the group answers with an announcement.
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 70, 76);
+
+const MAX_DATAGRAM_SIZE: usize = 4096;
+
+// How often a manager repeats its probe while waiting for answers.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+// What a node tells managers about itself.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeAnnouncement {
+    pub protocol_version: u32,
+    pub flux_version: String,
+    pub hostname: String,
+    // The TCP port the node accepts managers on.
+    pub port: u16,
+    pub worker_info: WorkerInfo,
+    pub tls: bool,
+    pub auth: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum DiscoveryMessage {
+    Probe { protocol_version: u32 },
+    Announce(NodeAnnouncement),
+}
+
+// A node that answered a probe, and the address to connect to it on.
+#[derive(Clone, Debug)]
+pub struct DiscoveredNode {
+    pub address: SocketAddr,
+    pub announcement: NodeAnnouncement,
+}
+
+fn encode(msg: &DiscoveryMessage) -> io::Result<Vec<u8>> {
+    serde_cbor::to_vec(msg).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+}
+
+// Answer probes on the discovery group until an error occurs. Several
+// nodes on one host can listen on the same port.
+pub fn serve_discovery(port: u16, announcement: NodeAnnouncement) -> io::Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+
+    let socket: UdpSocket = socket.into();
+    socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+
+    let reply = encode(&DiscoveryMessage::Announce(announcement))?;
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+
+        // Ignore anything that is not a probe, such as other nodes'
+        // announcements
+        match serde_cbor::from_slice(&buf[..len]) {
+            Ok(DiscoveryMessage::Probe { protocol_version }) => {
+                d_println(format!("Discovery probe from {} (protocol version {})", peer, protocol_version));
+                if let Err(e) = socket.send_to(&reply, peer) {
+                    println!("Could not answer discovery probe from {}: {}", peer, e);
+                }
+            },
+            Ok(DiscoveryMessage::Announce(_)) => (),
+            Err(_) => d_println(format!("Ignoring invalid discovery datagram from {}", peer)),
+        }
+    }
+}
+
+// Probe for nodes on the local network and collect the ones that answer
+// within the given window.
+pub fn discover_nodes(port: u16, window: Duration) -> io::Result<Vec<DiscoveredNode>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_loop_v4(true)?;
+
+    let probe = encode(&DiscoveryMessage::Probe { protocol_version: PROTOCOL_VERSION })?;
+
+    let start = Instant::now();
+    let mut next_probe = start;
+    let mut nodes: Vec<DiscoveredNode> = vec![];
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let now = Instant::now();
+        let elapsed = now - start;
+        if elapsed >= window {
+            break;
+        }
+
+        // Probes and announcements are single datagrams that can be lost,
+        // so keep probing until the window closes
+        if now >= next_probe {
+            socket.send_to(&probe, (DISCOVERY_GROUP, port))?;
+            next_probe = now + PROBE_INTERVAL;
+        }
+
+        let wait = (window - elapsed).min(next_probe - now);
+        socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+
+        match serde_cbor::from_slice(&buf[..len]) {
+            Ok(DiscoveryMessage::Announce(announcement)) => {
+                let address = SocketAddr::new(peer.ip(), announcement.port);
+                if !nodes.iter().any(|n| n.address == address) {
+                    nodes.push(DiscoveredNode { address, announcement });
+                }
+            },
+            _ => d_println(format!("Ignoring unexpected discovery datagram from {}", peer)),
+        }
+    }
+
+    Ok(nodes)
+}
@@ -0,0 +1,208 @@
+
+use std::io;
+use std::str::FromStr;
+
+use half::f16;
+use serde_bytes::ByteBuf;
+
+use crate::color::Color;
+use crate::manager::{RenderEvent, WorkUnitResult};
+use crate::job::WorkUnit;
+
+// The deflate level used for compressed results; higher levels cost a
+// lot more time for little gain on pixel data.
+const COMPRESSION_LEVEL: u8 = 3;
+
+// The precision of the pixels in work unit results sent by a node.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+#[derive(PartialEq)]
+pub enum PixelEncoding {
+    F64,
+    F32,
+    // Half-precision floats; values too large for them are clamped.
+    F16,
+}
+
+impl PixelEncoding {
+    pub fn all() -> Vec<PixelEncoding> {
+        vec![PixelEncoding::F64, PixelEncoding::F32, PixelEncoding::F16]
+    }
+
+    fn value_size(&self) -> usize {
+        match self {
+            PixelEncoding::F64 => 8,
+            PixelEncoding::F32 => 4,
+            PixelEncoding::F16 => 2,
+        }
+    }
+
+    fn write(&self, v: f64, out: &mut Vec<u8>) {
+        match self {
+            PixelEncoding::F64 => out.extend_from_slice(&v.to_le_bytes()),
+            PixelEncoding::F32 => out.extend_from_slice(&(v as f32).to_le_bytes()),
+            PixelEncoding::F16 => {
+                let h = f16::from_f64(v.min(f16::MAX.to_f64()));
+                out.extend_from_slice(&h.to_le_bytes())
+            },
+        }
+    }
+
+    fn read(&self, bytes: &[u8]) -> f64 {
+        match self {
+            PixelEncoding::F64 => {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(bytes);
+                f64::from_le_bytes(b)
+            },
+            PixelEncoding::F32 => {
+                let mut b = [0u8; 4];
+                b.copy_from_slice(bytes);
+                f32::from_le_bytes(b) as f64
+            },
+            PixelEncoding::F16 => f16::from_le_bytes([bytes[0], bytes[1]]).to_f64(),
+        }
+    }
+}
+
+impl FromStr for PixelEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f64" => Ok(PixelEncoding::F64),
+            "f32" => Ok(PixelEncoding::F32),
+            "f16" => Ok(PixelEncoding::F16),
+            _ => Err(format!("unknown pixel encoding '{}', expected one of f64, f32, f16", s)),
+        }
+    }
+}
+
+// How a node sends results to its manager, chosen by the manager when it
+// connects. The default sends RenderEvents as they are; anything else
+// sends the pixels of each result as planes of packed values, optionally
+// deflate-compressed.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+#[derive(PartialEq)]
+pub struct WireEncoding {
+    pub pixels: PixelEncoding,
+    pub compress: bool,
+}
+
+impl Default for WireEncoding {
+    fn default() -> Self {
+        Self {
+            pixels: PixelEncoding::F64,
+            compress: false,
+        }
+    }
+}
+
+impl WireEncoding {
+    pub fn is_compact(&self) -> bool {
+        *self != WireEncoding::default()
+    }
+}
+
+// A work unit result in a compact encoding: the red, green and blue
+// planes of the tile, then its sample counts as u32s, all little-endian.
+#[derive(Serialize, Deserialize)]
+pub struct EncodedRows {
+    pub work_unit: WorkUnit,
+    pub data: ByteBuf,
+}
+
+// What a node sends when using a compact encoding.
+#[derive(Serialize, Deserialize)]
+pub enum EncodedEvent {
+    Rows(EncodedRows),
+    Event(RenderEvent),
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn encode_event(ev: RenderEvent, encoding: WireEncoding) -> EncodedEvent {
+    let r = match ev {
+        RenderEvent::RowsReady(r) => r,
+        other => return EncodedEvent::Event(other),
+    };
+
+    let pixels = r.work_unit.width() * r.work_unit.height();
+    let mut data = Vec::with_capacity(pixels * (3 * encoding.pixels.value_size() + 4));
+
+    let channels: [fn(&Color) -> f64; 3] = [|c| c.r, |c| c.g, |c| c.b];
+    for channel in channels.iter() {
+        for c in r.rows.iter().flatten() {
+            encoding.pixels.write(channel(c), &mut data);
+        }
+    }
+    for n in r.sample_counts.iter().flatten() {
+        data.extend_from_slice(&(*n as u32).to_le_bytes());
+    }
+
+    if encoding.compress {
+        data = miniz_oxide::deflate::compress_to_vec(&data, COMPRESSION_LEVEL);
+    }
+
+    EncodedEvent::Rows(EncodedRows {
+        work_unit: r.work_unit,
+        data: ByteBuf::from(data),
+    })
+}
+
+pub fn decode_event(ev: EncodedEvent, encoding: WireEncoding) -> io::Result<RenderEvent> {
+    let rows = match ev {
+        EncodedEvent::Event(ev) => return Ok(ev),
+        EncodedEvent::Rows(rows) => rows,
+    };
+
+    let unit = rows.work_unit;
+    let (width, height) = (unit.width(), unit.height());
+    let pixels = width * height;
+    let value_size = encoding.pixels.value_size();
+    let expected_len = pixels * (3 * value_size + 4);
+
+    let data = if encoding.compress {
+        miniz_oxide::inflate::decompress_to_vec_with_limit(&rows.data, expected_len)
+            .map_err(|e| invalid(format!("could not decompress result for {:?}: {:?}", unit, e.status)))?
+    } else {
+        rows.data.into_vec()
+    };
+
+    if data.len() != expected_len {
+        return Err(invalid(format!("result for {:?} has {} bytes, expected {}", unit, data.len(), expected_len)));
+    }
+
+    let value = |plane: usize, i: usize| {
+        let start = (plane * pixels + i) * value_size;
+        encoding.pixels.read(&data[start..start + value_size])
+    };
+    let counts = &data[3 * pixels * value_size..];
+
+    let mut result_rows = Vec::with_capacity(height);
+    let mut sample_counts = Vec::with_capacity(height);
+    for y in 0..height {
+        let row = (0..width).map(|x| {
+            let i = y * width + x;
+            Color::new(value(0, i), value(1, i), value(2, i))
+        }).collect();
+        let count_row = (0..width).map(|x| {
+            let i = (y * width + x) * 4;
+            u32::from_le_bytes([counts[i], counts[i + 1], counts[i + 2], counts[i + 3]]) as usize
+        }).collect();
+        result_rows.push(row);
+        sample_counts.push(count_row);
+    }
+
+    Ok(RenderEvent::RowsReady(WorkUnitResult {
+        work_unit: unit,
+        rows: result_rows,
+        sample_counts,
+        worker: String::new(),
+    }))
+}
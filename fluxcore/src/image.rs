@@ -1,20 +1,127 @@
 
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufWriter;
 use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use png::HasParameters;
 
 use crate::color::Color;
+use crate::tonemap::ToneMapping;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum ImageFormat {
+    Ppm,
+    Png8,
+    Png16,
+    Exr,
+}
+
+impl ImageFormat {
+    // Guess the format from a file name's extension. PNG files default
+    // to 8 bits per channel.
+    pub fn from_path(path: &str) -> Option<ImageFormat> {
+        let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "ppm" => Some(ImageFormat::Ppm),
+            "png" => Some(ImageFormat::Png8),
+            "exr" => Some(ImageFormat::Exr),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png8 | ImageFormat::Png16 => "png",
+            ImageFormat::Exr => "exr",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ppm" => Ok(ImageFormat::Ppm),
+            "png" | "png8" => Ok(ImageFormat::Png8),
+            "png16" => Ok(ImageFormat::Png16),
+            "exr" => Ok(ImageFormat::Exr),
+            _ => Err(format!("unknown image format '{}', expected one of ppm, png8, png16, exr", s)),
+        }
+    }
+}
+
+// Where and how a finished image should be written.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct ImageOutput {
+    pub path: String,
+    pub format: ImageFormat,
+    pub tone_mapping: ToneMapping,
+}
+
+impl ImageOutput {
+    // Write the image to a temporary file next to the output and then
+    // move it into place, so that anyone watching the output never sees
+    // a partly written file.
+    pub fn write(&self, img: &Image) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", self.path);
+        {
+            let mut f = File::create(&tmp_path)?;
+            img.write_format(&mut f, self.format, &self.tone_mapping)?;
+        }
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+// Unrendered pixels in partial images are shown as a checkerboard of
+// these colors, with squares of this many pixels.
+const UNRENDERED_COLORS: [Color; 2] = [
+    Color { r: 1.0, g: 0.0, b: 1.0 },
+    Color { r: 0.25, g: 0.25, b: 0.25 },
+];
+const UNRENDERED_SQUARE_SIZE: usize = 8;
+
+// OpenEXR header attribute, as name, type name and value bytes.
+fn write_exr_attribute<W: Write>(w: &mut W, name: &str, type_name: &str, value: &[u8]) -> io::Result<()> {
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(type_name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(&(value.len() as i32).to_le_bytes())?;
+    w.write_all(value)
+}
+
+fn le_bytes_i32(vals: &[i32]) -> Vec<u8> {
+    vals.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
 
 pub struct Image {
     pub height: usize,
     pub width: usize,
     pub pixels: Vec<Vec<Color>>,
+    // The number of samples that have been averaged into each pixel.
+    pub sample_counts: Vec<Vec<usize>>,
+    // Incremented every time a row changes, so that viewers can tell
+    // which rows need to be redrawn.
+    pub row_revisions: Vec<usize>,
 }
 
 impl Image {
     pub fn new(w: usize, h: usize) -> Self {
         Self {
-            pixels: (0..h).map(|_| vec![]).collect(),
+            pixels: (0..h).map(|_| vec![Color::black(); w]).collect(),
+            sample_counts: (0..h).map(|_| vec![0; w]).collect(),
+            row_revisions: vec![0; h],
             width: w,
             height: h,
         }
@@ -22,6 +129,33 @@ impl Image {
 
     pub fn set_row(&mut self, row_index: usize, values: Vec<Color>) {
         self.pixels[row_index] = values;
+        self.row_revisions[row_index] += 1;
+    }
+
+    // Fold a span of pixel estimates starting at col_start, each the
+    // mean of the corresponding number of samples, into the running
+    // mean for that row.
+    pub fn accumulate_row(&mut self, row_index: usize, col_start: usize,
+                          values: &[Color], num_samples: &[usize]) {
+        let pixels = &mut self.pixels[row_index];
+        let counts = &mut self.sample_counts[row_index];
+
+        for (i, (value, num_samples)) in values.iter().zip(num_samples).enumerate() {
+            let col = col_start + i;
+            let num_samples = *num_samples;
+            let old_count = counts[col];
+            let new_count = old_count + num_samples;
+            pixels[col] = (pixels[col] * old_count as f64 + *value * num_samples as f64) *
+                (1.0 / new_count as f64);
+            counts[col] = new_count;
+        }
+
+        self.row_revisions[row_index] += 1;
+    }
+
+    // The smallest number of samples taken for any pixel so far.
+    pub fn min_sample_count(&self) -> usize {
+        self.sample_counts.iter().flat_map(|r| r.iter()).cloned().min().unwrap_or(0)
     }
 
     pub fn set_pixel(&mut self, row_index: usize, col_index: usize, value: Color) {
@@ -38,14 +172,56 @@ impl Image {
         }
 
         self.pixels[row_index][col_index] = value;
+        self.row_revisions[row_index] += 1;
+    }
+
+    // The pixel at the given position, or black if it has not been set.
+    fn pixel(&self, row_index: usize, col_index: usize) -> Color {
+        match self.pixels[row_index].get(col_index) {
+            Some(c) => *c,
+            None => Color::black(),
+        }
     }
 
-    pub fn write(&self, f: &mut File) {
+    // A copy of the image in which the pixels that have no samples yet
+    // are marked, for writing out a render that is still in progress.
+    pub fn marking_unrendered(&self) -> Image {
+        let mut img = Image::new(self.width, self.height);
+
+        for row in 0..self.height {
+            for col in 0..self.width {
+                img.pixels[row][col] = if self.sample_counts[row][col] > 0 {
+                    self.pixel(row, col)
+                } else {
+                    let square = row / UNRENDERED_SQUARE_SIZE + col / UNRENDERED_SQUARE_SIZE;
+                    UNRENDERED_COLORS[square % 2]
+                };
+            }
+        }
+
+        img.sample_counts = self.sample_counts.clone();
+        img
+    }
+
+    pub fn write_format(&self, f: &mut File, format: ImageFormat, tm: &ToneMapping) -> io::Result<()> {
+        match format {
+            ImageFormat::Ppm => {
+                self.write(f, tm);
+                Ok(())
+            },
+            ImageFormat::Png8 => self.write_png(f, png::BitDepth::Eight, tm),
+            ImageFormat::Png16 => self.write_png(f, png::BitDepth::Sixteen, tm),
+            ImageFormat::Exr => self.write_exr(f),
+        }
+    }
+
+    pub fn write(&self, f: &mut File, tm: &ToneMapping) {
         let mut buf = BufWriter::new(f);
 
         write!(buf, "P3\n{} {}\n65535\n", self.width, self.height).unwrap();
         for row in &self.pixels {
-            for pixel in row {
+            for raw_pixel in row {
+                let pixel = tm.apply(raw_pixel);
                 write!(buf, "{} {} {}\n",
                        (pixel.r * 65535.99) as u16,
                        (pixel.g * 65535.99) as u16,
@@ -59,4 +235,96 @@ impl Image {
             }
         }
     }
+
+    pub fn write_png(&self, f: &mut File, depth: png::BitDepth, tm: &ToneMapping) -> io::Result<()> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.width * self.height * 6);
+
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let pixel = tm.apply(&self.pixel(row, col));
+                for v in &[pixel.r, pixel.g, pixel.b] {
+                    match depth {
+                        png::BitDepth::Sixteen => {
+                            data.extend_from_slice(&((v * 65535.99) as u16).to_be_bytes());
+                        },
+                        _ => {
+                            data.push((v * 255.99) as u8);
+                        },
+                    }
+                }
+            }
+        }
+
+        let mut encoder = png::Encoder::new(BufWriter::new(f), self.width as u32, self.height as u32);
+        encoder.set(png::ColorType::RGB).set(depth);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+
+        Ok(())
+    }
+
+    // Write an uncompressed scanline OpenEXR file with 32-bit float
+    // channels. Unlike the other formats, this stores the radiance
+    // values as they are, without clamping.
+    pub fn write_exr(&self, f: &mut File) -> io::Result<()> {
+        let mut buf = BufWriter::new(f);
+        let w = self.width as i32;
+        let h = self.height as i32;
+
+        let mut header: Vec<u8> = vec![];
+        header.extend_from_slice(&20000630i32.to_le_bytes());
+        header.extend_from_slice(&2i32.to_le_bytes());
+
+        // Channels must be listed in alphabetical order.
+        let mut channels: Vec<u8> = vec![];
+        for name in &["B", "G", "R"] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            // Pixel type FLOAT, pLinear and reserved bytes, then x and y
+            // sampling.
+            channels.extend_from_slice(&le_bytes_i32(&[2, 0, 1, 1]));
+        }
+        channels.push(0);
+
+        write_exr_attribute(&mut header, "channels", "chlist", &channels)?;
+        write_exr_attribute(&mut header, "compression", "compression", &[0])?;
+        write_exr_attribute(&mut header, "dataWindow", "box2i", &le_bytes_i32(&[0, 0, w - 1, h - 1]))?;
+        write_exr_attribute(&mut header, "displayWindow", "box2i", &le_bytes_i32(&[0, 0, w - 1, h - 1]))?;
+        write_exr_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+        write_exr_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes())?;
+        write_exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+        write_exr_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes())?;
+        header.push(0);
+
+        buf.write_all(&header)?;
+
+        // Each scanline is its own block: the y coordinate and data size,
+        // followed by each channel's values for the whole line.
+        let line_size = self.width * 3 * 4;
+        let block_size = (4 + 4 + line_size) as u64;
+        let first_block = (header.len() + self.height * 8) as u64;
+
+        for y in 0..self.height as u64 {
+            buf.write_all(&(first_block + y * block_size).to_le_bytes())?;
+        }
+
+        for row in 0..self.height {
+            buf.write_all(&(row as i32).to_le_bytes())?;
+            buf.write_all(&(line_size as i32).to_le_bytes())?;
+
+            for channel in 0..3 {
+                for col in 0..self.width {
+                    let pixel = self.pixel(row, col);
+                    let v = match channel {
+                        0 => pixel.b,
+                        1 => pixel.g,
+                        _ => pixel.r,
+                    };
+                    buf.write_all(&(v as f32).to_le_bytes())?;
+                }
+            }
+        }
+
+        buf.flush()
+    }
 }
@@ -0,0 +1,78 @@
+
+use std::sync::Arc;
+
+use nalgebra::{Vector3, Point3, Matrix3, Rotation3};
+
+use crate::common::*;
+use crate::shapes::BoundingBox;
+
+// A shape placed in the scene with a transform. Rays are transformed
+// into the shape's own space to be intersected, and the resulting hits
+// transformed back. The shape itself may be shared by many instances.
+pub struct Instance {
+    pub shape: Arc<dyn Intersectable>,
+    // The inverse of the transform from the shape's space to the scene,
+    // without the translation.
+    pub inverse: Matrix3<f64>,
+    pub translation: Vector3<f64>,
+    pub bbox: Option<BoundingBox>,
+}
+
+impl Instance {
+    // The rotation is in degrees; see InstanceData.
+    pub fn new(shape: Arc<dyn Intersectable>, translate: Vector3<f64>, rotate: Vector3<f64>,
+               scale: Vector3<f64>) -> Self {
+        let r = rotate * std::f64::consts::PI / 180.0;
+        let rotation = Rotation3::from_euler_angles(r.x, r.y, r.z);
+        let matrix = rotation.matrix() * Matrix3::from_diagonal(&scale);
+        let inverse = match matrix.try_inverse() {
+            Some(m) => m,
+            None => panic!("Instance::new: transform with scale {:?} is not invertible", scale),
+        };
+
+        // The bounds of the transformed corners of the shape's bounds
+        let bbox = shape.bounding_box().map(|b| {
+            let corners: Vec<Point3<f64>> = (0..8).map(|i| {
+                let p = Point3::new(if i & 1 == 0 { b.corner0.x } else { b.corner1.x },
+                                    if i & 2 == 0 { b.corner0.y } else { b.corner1.y },
+                                    if i & 4 == 0 { b.corner0.z } else { b.corner1.z });
+                Point3::from(matrix * p.coords + translate)
+            }).collect();
+            BoundingBox::from_points(corners.iter())
+        });
+
+        Self {
+            shape,
+            inverse,
+            translation: translate,
+            bbox,
+        }
+    }
+}
+
+impl Intersectable for Instance {
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        self.bbox
+    }
+
+    fn hit<'a>(&'a self, r: &Ray, depth: usize) -> Option<Hit<'a>> {
+        // The direction is not normalized, so that distances along the
+        // transformed ray are the same as along the original one.
+        let local = Ray {
+            origin: Point3::from(self.inverse * (r.origin.coords - self.translation)),
+            direction: self.inverse * r.direction,
+        };
+
+        self.shape.hit(&local, depth).map(|h| {
+            Hit {
+                ray: r.clone(),
+                depth,
+                distance: h.distance,
+                normal: (self.inverse.transpose() * h.normal).normalize(),
+                uv: h.uv,
+                local_hit_point: r.origin + h.distance * r.direction,
+                material: h.material,
+            }
+        })
+    }
+}
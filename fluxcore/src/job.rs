@@ -1,11 +1,18 @@
 
+use std::str::FromStr;
+
 use rand::Rng;
 
 use crate::scene::SceneData;
 
+// Tiles are not split near the end of a job if the halves would be
+// smaller than this in either dimension.
+const MIN_SPLIT_TILE_SIZE: usize = 8;
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Eq)]
+#[derive(Hash)]
 #[derive(Clone)]
 #[derive(Copy)]
 #[derive(Serialize, Deserialize)]
@@ -36,20 +43,129 @@ impl JobIDAllocator {
 #[derive(Debug)]
 #[derive(Clone)]
 #[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Hash)]
 #[derive(Serialize, Deserialize)]
 pub struct WorkUnit {
+    // The tile of the image to render, with inclusive bounds.
     pub row_start: usize,
     pub row_end: usize,
+    pub col_start: usize,
+    pub col_end: usize,
+    // The sample pass this unit belongs to, starting at zero.
+    pub pass: usize,
     pub job_id: JobID,
 }
 
+impl WorkUnit {
+    pub fn width(&self) -> usize {
+        self.col_end - self.col_start + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.row_end - self.row_start + 1
+    }
+
+    // Split the tile in two across its longer side, unless that would
+    // make tiles smaller than MIN_SPLIT_TILE_SIZE.
+    pub fn split(&self) -> Option<(WorkUnit, WorkUnit)> {
+        if self.height() >= self.width() {
+            if self.height() < 2 * MIN_SPLIT_TILE_SIZE {
+                return None;
+            }
+            let mid = self.row_start + self.height() / 2;
+            Some((WorkUnit { row_end: mid - 1, ..*self }, WorkUnit { row_start: mid, ..*self }))
+        } else {
+            if self.width() < 2 * MIN_SPLIT_TILE_SIZE {
+                return None;
+            }
+            let mid = self.col_start + self.width() / 2;
+            Some((WorkUnit { col_end: mid - 1, ..*self }, WorkUnit { col_start: mid, ..*self }))
+        }
+    }
+}
+
+// The order in which the tiles of each pass are handed out.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+#[derive(PartialEq)]
+pub enum TileOrder {
+    // Left to right, top to bottom.
+    Scanline,
+    // Outwards from the center of the image, so the usual subject of
+    // the image is rendered first.
+    Spiral,
+    // Along a Hilbert curve, so consecutive tiles are adjacent.
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unknown tile order '{}', expected one of scanline, spiral, hilbert", s)),
+        }
+    }
+}
+
+// The distance of (x, y) along a Hilbert curve filling an n x n grid,
+// where n is a power of two.
+fn hilbert_index(n: usize, x: usize, y: usize) -> usize {
+    let mut x = x;
+    let mut y = y;
+    let mut d = 0;
+    let mut s = n / 2;
+
+    while s > 0 {
+        let rx = if x & s > 0 { 1 } else { 0 };
+        let ry = if y & s > 0 { 1 } else { 0 };
+        d += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant so the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        s /= 2;
+    }
+
+    d
+}
+
 #[derive(Clone)]
 #[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JobConfiguration {
     pub sample_root: usize,
     pub max_trace_depth: usize,
-    pub rows_per_work_unit: usize,
+    // Work units are square tiles of this many pixels on a side,
+    // except at the right and bottom edges of the image.
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    // The number of sample passes to make over the image, each taking
+    // sample_root^2 samples per pixel. Zero means keep making passes
+    // until the job is cancelled.
+    pub passes: usize,
+    // Adaptive sampling: when the noise threshold is positive, each
+    // pixel in a pass keeps taking batches of sample_root^2 samples
+    // until it has at least min_samples samples and the relative
+    // standard error of its luminance is below the threshold, or until
+    // it reaches max_samples. Otherwise every pixel takes exactly one
+    // batch per pass. Adaptive sampling needs passes to be 1, since the
+    // statistics are not carried from one pass to the next.
+    pub noise_threshold: f64,
+    pub min_samples: usize,
+    pub max_samples: usize,
 }
 
 // A job provides all the resources and configuration needed to render a
@@ -63,27 +179,71 @@ pub struct Job {
 }
 
 impl Job {
-    pub fn work_units(&self) -> Vec<WorkUnit> {
-        if self.config.rows_per_work_unit == 0 {
-            panic!("Job row per work unit count invalid: {}",
-                   self.config.rows_per_work_unit);
+    // The work units for every pass of the job, in pass order. This is
+    // endless if the job has an unlimited number of passes.
+    pub fn work_units(&self) -> Box<dyn Iterator<Item=WorkUnit> + Send> {
+        self.work_units_from(0)
+    }
+
+    // The work units for the passes from first_pass onwards.
+    pub fn work_units_from(&self, first_pass: usize) -> Box<dyn Iterator<Item=WorkUnit> + Send> {
+        let units = self.pass_work_units(0);
+        let passes: Box<dyn Iterator<Item=usize> + Send> = match self.config.passes {
+            0 => Box::new(first_pass..),
+            n => Box::new(first_pass..n),
+        };
+
+        Box::new(passes.flat_map(move |pass| {
+            units.clone().into_iter().map(move |u| WorkUnit { pass, ..u })
+        }))
+    }
+
+    pub fn pass_work_units(&self, pass: usize) -> Vec<WorkUnit> {
+        let tile_size = self.config.tile_size;
+        if tile_size == 0 {
+            panic!("Job tile size invalid: {}", tile_size);
         }
 
-        let mut us = Vec::new();
-        let mut i = 0;
+        let width = self.scene_data.output_settings.image_width;
+        let height = self.scene_data.output_settings.image_height;
+        let tiles_x = (width + tile_size - 1) / tile_size;
+        let tiles_y = (height + tile_size - 1) / tile_size;
 
-        while i < self.scene_data.output_settings.image_height - 1 {
-            let remaining_rows = self.scene_data.output_settings.image_height - i;
-            let num_rows = std::cmp::min(self.config.rows_per_work_unit, remaining_rows);
-            let u = WorkUnit {
-                row_start: i,
-                row_end: i + num_rows - 1,
-                job_id: self.id,
-            };
-            us.push(u);
-            i += num_rows;
+        let mut tiles: Vec<(usize, usize)> = (0..tiles_y)
+            .flat_map(|ty| (0..tiles_x).map(move |tx| (tx, ty)))
+            .collect();
+
+        match self.config.tile_order {
+            TileOrder::Scanline => (),
+            TileOrder::Spiral => {
+                // Order by square ring around the center, then by angle
+                // within each ring.
+                let cx = (tiles_x as f64 - 1.0) * 0.5;
+                let cy = (tiles_y as f64 - 1.0) * 0.5;
+                let key = |&(tx, ty): &(usize, usize)| {
+                    let dx = tx as f64 - cx;
+                    let dy = ty as f64 - cy;
+                    (dx.abs().max(dy.abs()), dy.atan2(dx))
+                };
+                tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            },
+            TileOrder::Hilbert => {
+                let n = std::cmp::max(tiles_x, tiles_y).next_power_of_two();
+                tiles.sort_by_key(|&(tx, ty)| hilbert_index(n, tx, ty));
+            },
         }
 
-        us
+        tiles.into_iter().map(|(tx, ty)| {
+            let col_start = tx * tile_size;
+            let row_start = ty * tile_size;
+            WorkUnit {
+                row_start,
+                row_end: std::cmp::min(row_start + tile_size, height) - 1,
+                col_start,
+                col_end: std::cmp::min(col_start + tile_size, width) - 1,
+                pass,
+                job_id: self.id,
+            }
+        }).collect()
     }
 }
@@ -6,13 +6,26 @@ extern crate samplers;
 pub mod job;
 pub mod brdf;
 pub mod materials;
+pub mod texture;
 pub mod image;
+pub mod tonemap;
 pub mod color;
 pub mod shapes;
+pub mod mesh;
+pub mod instance;
+pub mod bvh;
+pub mod lights;
 pub mod sampling;
 pub mod workers;
+pub mod protocol;
+pub mod net;
+pub mod discovery;
+pub mod encoding;
+pub mod checkpoint;
+pub mod progress;
 pub mod debug;
 pub mod scene;
+pub mod validate;
 pub mod trace;
 pub mod constants;
 pub mod manager;
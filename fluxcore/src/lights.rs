@@ -0,0 +1,48 @@
+
+use std::sync::Arc;
+
+use nalgebra::Point3;
+
+use crate::color::Color;
+use crate::common::Sampleable;
+use crate::shapes::EmissiveData;
+use crate::texture::{Texture, texture_from_data};
+
+// An emissive shape that can be sampled directly when computing the
+// light arriving at a surface.
+pub struct AreaLight {
+    pub shape: Box<dyn Sampleable>,
+    pub color: Box<dyn Texture>,
+    pub power: f64,
+}
+
+impl AreaLight {
+    pub fn new(shape: Box<dyn Sampleable>, e: &EmissiveData) -> Arc<Self> {
+        Arc::new(Self {
+            shape,
+            color: texture_from_data(&e.color),
+            power: e.power,
+        })
+    }
+
+    // The light emitted from the point `p` on this light.
+    pub fn radiance_at(&self, p: &Point3<f64>) -> Color {
+        self.color.color_at(&self.shape.uv_at(p), p) * self.power
+    }
+
+    // The density, with respect to solid angle as seen from `origin`,
+    // of sampling the point `p` on this light. This is zero if `p`
+    // faces away from `origin`, since lights only emit from their front
+    // side.
+    pub fn pdf(&self, origin: &Point3<f64>, p: &Point3<f64>) -> f64 {
+        let d = p - origin;
+        let dist_squared = d.norm_squared();
+        let cos_light = -self.shape.normal_at(p).dot(&d) / dist_squared.sqrt();
+
+        if cos_light <= 0.0 {
+            0.0
+        } else {
+            self.shape.pdf() * dist_squared / cos_light
+        }
+    }
+}
@@ -1,16 +1,17 @@
 
-use crossbeam::channel::{Sender, Receiver, bounded, unbounded};
+use crossbeam::channel::{Sender, Receiver, unbounded};
 use crossbeam::sync::WaitGroup;
 use crossbeam::SendError;
-use std::fs::File;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::thread;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::scene::{SceneData};
 use crate::color::Color;
-use crate::image::Image;
+use crate::image::{Image, ImageOutput};
 use crate::job::{JobConfiguration, Job, JobID, JobIDAllocator, WorkUnit};
+use crate::checkpoint::Checkpointer;
 use crate::debug::d_println;
 
 #[derive(Serialize, Deserialize)]
@@ -24,7 +25,14 @@ pub enum RenderEvent {
 #[derive(Serialize, Deserialize)]
 pub struct WorkUnitResult {
     pub work_unit: WorkUnit,
+    // The pixels of the work unit's tile, row by row.
     pub rows: Vec<Vec<Color>>,
+    // The number of samples taken for each pixel in the rows.
+    pub sample_counts: Vec<Vec<usize>>,
+    // The worker that delivered the result to the manager. Results from
+    // network nodes are named by the receiving end, so this is not sent.
+    #[serde(skip)]
+    pub worker: String,
 }
 
 pub struct RenderManager {
@@ -33,8 +41,9 @@ pub struct RenderManager {
     thread_handle: thread::JoinHandle<()>,
 }
 
-pub type WorkerRequest = Option<(Box<Job>, Receiver<WorkUnit>, Sender<Option<RenderEvent>>, WaitGroup)>;
-type ScheduledJob = Option<(Job, Sender<()>, Receiver<()>, Sender<Option<RenderEvent>>)>;
+pub type WorkerRequest = Option<(Box<Job>, WorkQueueHandle, Sender<Option<RenderEvent>>, WaitGroup)>;
+pub type WorkUnits = Box<dyn Iterator<Item=WorkUnit> + Send>;
+type ScheduledJob = Option<(Job, WorkUnits, Sender<()>, Receiver<()>, Sender<Option<RenderEvent>>)>;
 
 pub struct WorkerHandle {
     sender: Sender<WorkerRequest>,
@@ -47,8 +56,299 @@ impl WorkerHandle {
         }
     }
 
-    pub fn send(&self, j: Box<Job>, r: Receiver<WorkUnit>, s: Sender<Option<RenderEvent>>, wg: WaitGroup) -> Result<(), SendError<WorkerRequest>> {
-        self.sender.send(Some((j, r, s, wg)))
+    pub fn send(&self, j: Box<Job>, q: WorkQueueHandle, s: Sender<Option<RenderEvent>>, wg: WaitGroup) -> Result<(), SendError<WorkerRequest>> {
+        self.sender.send(Some((j, q, s, wg)))
+    }
+}
+
+// The work units of a job that have yet to be rendered. Workers take
+// units from the queue and report each one as complete once its result
+// is in; if a worker fails, the units it had taken are put back on the
+// queue for the remaining workers.
+//
+// So that a slow worker does not hold up the end of a job, the last
+// units are split into smaller ones as the other workers ask for them,
+// and a worker with nothing left to do is given a copy of the oldest
+// unit still outstanding elsewhere. Whichever copy completes first is
+// kept.
+pub struct WorkQueue {
+    state: Mutex<WorkQueueState>,
+    changed: Condvar,
+}
+
+struct WorkQueueState {
+    // Units to hand out before drawing from the source: requeued units
+    // and units pushed onto an open queue.
+    pending: VecDeque<WorkUnit>,
+    // The job's units, or None once they have all been handed out.
+    source: Option<Box<dyn Iterator<Item=WorkUnit> + Send>>,
+    // Whether more units may still be pushed onto the queue.
+    open: bool,
+    cancelled: bool,
+    // The units each worker has taken but not yet completed, by worker.
+    outstanding: HashMap<usize, Vec<WorkUnit>>,
+    // When each outstanding unit was first taken.
+    taken_at: HashMap<WorkUnit, Instant>,
+    // Outstanding units that have also been given to a second worker.
+    duplicated: HashSet<WorkUnit>,
+    // The workers holding a handle to the queue.
+    workers: HashSet<usize>,
+}
+
+impl WorkQueueState {
+    fn is_drained(&self) -> bool {
+        self.pending.is_empty() && self.source.is_none() && !self.open
+    }
+
+    // Draw units from the source ahead of time, enough for each worker
+    // to have one, so that the last units are known before they are
+    // handed out.
+    fn fill_pending(&mut self) {
+        while self.pending.len() < self.workers.len().max(1) {
+            match self.source.as_mut().and_then(|s| s.next()) {
+                Some(u) => self.pending.push_back(u),
+                None => {
+                    self.source = None;
+                    return;
+                },
+            }
+        }
+    }
+
+    // Once the source is exhausted, split a unit taken from pending if
+    // there are not enough units left for the other workers.
+    fn split_for_tail(&mut self, unit: WorkUnit) -> WorkUnit {
+        let in_tail = self.source.is_none() && !self.open;
+        if !in_tail || self.pending.len() + 1 >= self.workers.len() {
+            return unit;
+        }
+
+        match unit.split() {
+            None => unit,
+            Some((first, second)) => {
+                d_println(format!("Work queue: split {:?} for the end of the job", unit));
+                self.pending.push_front(second);
+                first
+            },
+        }
+    }
+
+    // The oldest unit outstanding with another worker that has not
+    // already been duplicated.
+    fn duplicate_for(&mut self, worker: usize) -> Option<WorkUnit> {
+        let own: &[WorkUnit] = self.outstanding.get(&worker).map_or(&[], |us| us.as_slice());
+        let unit = self.outstanding.iter()
+            .filter(|(w, _)| **w != worker)
+            .flat_map(|(_, us)| us.iter())
+            .filter(|u| !own.contains(u) && !self.duplicated.contains(u))
+            .min_by_key(|u| self.taken_at.get(u))
+            .cloned()?;
+
+        d_println(format!("Work queue: duplicating {:?} for worker {}", unit, worker));
+        self.duplicated.insert(unit);
+        Some(unit)
+    }
+
+    fn assign(&mut self, worker: usize, unit: WorkUnit) {
+        self.outstanding.entry(worker).or_insert_with(Vec::new).push(unit);
+        self.taken_at.entry(unit).or_insert_with(Instant::now);
+    }
+}
+
+impl WorkQueue {
+    pub fn new(units: Box<dyn Iterator<Item=WorkUnit> + Send>) -> Arc<Self> {
+        Self::with_state(Some(units), false)
+    }
+
+    // A queue that starts out empty and is filled with push until it is
+    // closed.
+    pub fn open() -> Arc<Self> {
+        Self::with_state(None, true)
+    }
+
+    fn with_state(source: Option<Box<dyn Iterator<Item=WorkUnit> + Send>>, open: bool) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(WorkQueueState {
+                pending: VecDeque::new(),
+                source,
+                open,
+                cancelled: false,
+                outstanding: HashMap::new(),
+                taken_at: HashMap::new(),
+                duplicated: HashSet::new(),
+                workers: HashSet::new(),
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    pub fn handle(self: &Arc<Self>, worker: usize) -> WorkQueueHandle {
+        self.state.lock().unwrap().workers.insert(worker);
+        WorkQueueHandle {
+            queue: Arc::clone(self),
+            worker,
+        }
+    }
+
+    pub fn push(&self, unit: WorkUnit) {
+        self.state.lock().unwrap().pending.push_back(unit);
+        self.changed.notify_all();
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().open = false;
+        self.changed.notify_all();
+    }
+
+    // Remove a unit that has not been taken yet.
+    pub fn withdraw(&self, unit: &WorkUnit) {
+        self.state.lock().unwrap().pending.retain(|u| u != unit);
+        self.changed.notify_all();
+    }
+
+    // Stop handing out units. Units already taken may still complete.
+    pub fn cancel(&self) {
+        {
+            let mut st = self.state.lock().unwrap();
+            st.cancelled = true;
+            st.pending.clear();
+        }
+        self.changed.notify_all();
+    }
+
+    // Whether every unit has been completed, or the queue was cancelled.
+    pub fn is_finished(&self) -> bool {
+        let st = self.state.lock().unwrap();
+        st.cancelled || (st.is_drained() && st.outstanding.values().all(|us| us.is_empty()))
+    }
+
+    // Take the next unit for a worker. If no unit is available and
+    // block is set, the worker is idle, so give it a duplicate of
+    // another worker's unit or else wait as long as units might still
+    // appear: while the queue is open or other workers have units
+    // outstanding that could be requeued.
+    fn take(&self, worker: usize, block: bool) -> Option<WorkUnit> {
+        let mut st = self.state.lock().unwrap();
+
+        loop {
+            if st.cancelled {
+                return None;
+            }
+
+            st.fill_pending();
+
+            let next = match st.pending.pop_front() {
+                Some(u) => Some(st.split_for_tail(u)),
+                None if block && st.is_drained() => st.duplicate_for(worker),
+                None => None,
+            };
+
+            if let Some(u) = next {
+                st.assign(worker, u);
+                return Some(u);
+            }
+
+            let more_possible = st.open || st.outstanding.values().any(|us| !us.is_empty());
+            if !block || !more_possible {
+                return None;
+            }
+
+            st = self.changed.wait(st).unwrap();
+        }
+    }
+
+    // Returns whether this is the first result for the unit; if another
+    // worker got there first, this result should be discarded.
+    fn complete(&self, worker: usize, unit: &WorkUnit) -> bool {
+        let first = {
+            let mut st = self.state.lock().unwrap();
+            let first = st.outstanding.get(&worker).map_or(false, |us| us.contains(unit));
+            if first {
+                for us in st.outstanding.values_mut() {
+                    us.retain(|u| u != unit);
+                }
+                st.taken_at.remove(unit);
+                st.duplicated.remove(unit);
+            }
+            first
+        };
+        self.changed.notify_all();
+        first
+    }
+
+    // Requeue the units of a failed worker, except those that another
+    // worker is also rendering.
+    fn fail(&self, worker: usize) -> usize {
+        let num_requeued = {
+            let mut st = self.state.lock().unwrap();
+            st.workers.remove(&worker);
+            let units = st.outstanding.remove(&worker).unwrap_or_default();
+            let mut num_requeued = 0;
+            for u in units.iter().rev() {
+                if st.duplicated.remove(u) {
+                    continue;
+                }
+                st.taken_at.remove(u);
+                if !st.cancelled {
+                    st.pending.push_front(*u);
+                    num_requeued += 1;
+                }
+            }
+            num_requeued
+        };
+        self.changed.notify_all();
+        num_requeued
+    }
+
+    fn release(&self, worker: usize) {
+        self.state.lock().unwrap().workers.remove(&worker);
+        self.changed.notify_all();
+    }
+}
+
+// A worker's view of a WorkQueue.
+pub struct WorkQueueHandle {
+    queue: Arc<WorkQueue>,
+    worker: usize,
+}
+
+impl WorkQueueHandle {
+    // The next unit to render, waiting if necessary; None means that
+    // there is no more work for this job.
+    pub fn next(&self) -> Option<WorkUnit> {
+        self.queue.take(self.worker, true)
+    }
+
+    // The next unit to render if one is available right now.
+    pub fn try_next(&self) -> Option<WorkUnit> {
+        self.queue.take(self.worker, false)
+    }
+
+    // Whether this worker has taken the unit and nobody has completed it
+    // yet.
+    pub fn holds(&self, unit: &WorkUnit) -> bool {
+        let st = self.queue.state.lock().unwrap();
+        st.outstanding.get(&self.worker).map_or(false, |us| us.contains(unit))
+    }
+
+    // Report that the result for a unit is in. Returns false if another
+    // worker already completed the unit, in which case the result is a
+    // duplicate and should be discarded.
+    pub fn complete(&self, unit: &WorkUnit) -> bool {
+        self.queue.complete(self.worker, unit)
+    }
+
+    // Report that this worker can no longer render; its outstanding
+    // units are requeued for the other workers. Returns the number of
+    // units requeued.
+    pub fn fail(&self) -> usize {
+        self.queue.fail(self.worker)
+    }
+}
+
+impl Drop for WorkQueueHandle {
+    fn drop(&mut self) {
+        self.queue.release(self.worker);
     }
 }
 
@@ -80,7 +380,7 @@ impl RenderManager {
         let handle = thread::Builder::new().name("RenderManager".to_string()).spawn(move || {
             d_println(format!("Render manager: awaiting job"));
 
-            while let Ok(Some((job, notify_done, notify_cancel, result_sender))) = r.recv() {
+            while let Ok(Some((job, units, notify_done, notify_cancel, result_sender))) = r.recv() {
                 d_println(format!("Render manager: got job {:?}", job.id));
 
                 let info_event = RenderEvent::ImageInfo {
@@ -97,12 +397,10 @@ impl RenderManager {
                     }
                 }
 
-                let (ws, wr) = bounded(1);
                 let wg = WaitGroup::new();
-                let units = job.work_units().into_iter();
-                let wu_queue = Arc::new(Mutex::new(CancellableIterator::new(units)));
+                let queue = WorkQueue::new(units);
 
-                let wu_queue_cancel = Arc::clone(&wu_queue);
+                let queue_cancel = Arc::clone(&queue);
                 thread::Builder::new().name(format!("Cancel listener for {:?}", job.id)).spawn(move || {
                     d_println(format!("Cancel listener waiting for cancel message"));
                     match notify_cancel.recv() {
@@ -112,32 +410,7 @@ impl RenderManager {
                         }
                     }
                     d_println(format!("Cancel listener got cancellation"));
-                    wu_queue_cancel.lock().unwrap().cancel();
-                }).unwrap();
-
-                let wu_queue_read = Arc::clone(&wu_queue);
-                let job_id = job.id.clone();
-                thread::Builder::new().name(format!("Work queue for {:?}", job.id)).spawn(move || {
-                    d_println(format!("Work queue producer starting"));
-                    loop {
-                        let mut q = wu_queue_read.lock().unwrap();
-                        match q.next() {
-                            None => {
-                                d_println(format!("Work unit iterator cancelled or empty, quitting"));
-                                return;
-                            },
-                            Some(i) => {
-                                d_println(format!("Work item ready, adding to queue"));
-                                match ws.send(i.clone()) {
-                                    Ok(_) => (),
-                                    Err(_) => {
-                                        d_println(format!("Work queue for {:?} exiting due to send failure", job_id));
-                                        return;
-                                    },
-                                }
-                            }
-                        }
-                    }
+                    queue_cancel.cancel();
                 }).unwrap();
 
                 d_println(format!("Render manager: work queue ready, sending job to workers"));
@@ -153,13 +426,18 @@ impl RenderManager {
                     }
                 }
 
-                workers.iter().for_each(|worker| {
+                // Workers that failed during an earlier job have shut
+                // down and can no longer accept jobs, so they are
+                // skipped; the job proceeds with whoever is left.
+                for (i, worker) in workers.iter().enumerate() {
                     let job_boxed = Box::new(job.clone());
-                    // Note that we panic on send failures here because
-                    // a worker that is down is bad enough that we can't
-                    // recover
-                    worker.send(job_boxed, wr.clone(), result_sender.clone(), wg.clone()).unwrap();
-                });
+                    match worker.send(job_boxed, queue.handle(i), result_sender.clone(), wg.clone()) {
+                        Ok(()) => (),
+                        Err(_) => {
+                            d_println(format!("Render manager: worker {} is no longer available", i));
+                        },
+                    }
+                }
 
                 d_println(format!("Render manager: waiting for job completion or cancellation"));
 
@@ -167,6 +445,10 @@ impl RenderManager {
 
                 d_println(format!("Render manager: all workers done"));
 
+                if !queue.is_finished() {
+                    println!("Job {:?} could not be completed: no remaining worker could render it", job.id);
+                }
+
                 let end_time = SystemTime::now();
                 match result_sender.send(Some(RenderEvent::RenderingFinished { end_time, })) {
                     Ok(_) => (),
@@ -197,14 +479,27 @@ impl RenderManager {
 
     pub fn schedule_job(&mut self, scene_data: &SceneData, config: JobConfiguration, result_sender: Sender<Option<RenderEvent>>) -> JobHandle {
         let id = self.job_id_allocator.next_id();
-        let (s, r): (Sender<()>, Receiver<()>) = unbounded();
-        let (cs, cr): (Sender<()>, Receiver<()>) = unbounded();
         let j = Job {
             scene_data: scene_data.clone(),
             config,
             id,
         };
-        self.job_queue.send(Some((j, s, cr, result_sender))).unwrap();
+        let units = j.work_units();
+        self.enqueue(j, units, result_sender)
+    }
+
+    // Schedule the given units of a job that was started earlier, such
+    // as the remaining units of a checkpointed job, under its original
+    // ID.
+    pub fn resume_job(&mut self, job: Job, units: WorkUnits, result_sender: Sender<Option<RenderEvent>>) -> JobHandle {
+        self.enqueue(job, units, result_sender)
+    }
+
+    fn enqueue(&mut self, job: Job, units: WorkUnits, result_sender: Sender<Option<RenderEvent>>) -> JobHandle {
+        let (s, r): (Sender<()>, Receiver<()>) = unbounded();
+        let (cs, cr): (Sender<()>, Receiver<()>) = unbounded();
+        let id = job.id;
+        self.job_queue.send(Some((job, units, s, cr, result_sender))).unwrap();
         JobHandle {
             job_id: id,
             waiter: r,
@@ -218,7 +513,7 @@ impl RenderManager {
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct WorkerInfo {
     pub num_threads: usize,
 }
@@ -235,44 +530,16 @@ pub trait Worker {
     fn info(&self) -> WorkerInfo;
 }
 
-pub struct ConsoleResultReporter {
-    sender: Sender<Option<RenderEvent>>,
-}
-
-impl ConsoleResultReporter {
-    pub fn new() -> Self {
-        let (s, r): (Sender<Option<RenderEvent>>, Receiver<Option<RenderEvent>>) = unbounded();
-
-        thread::Builder::new().name("ConsoleResultReporter".to_string()).spawn(move || {
-            while let Ok(Some(result)) = r.recv() {
-                match result {
-                    RenderEvent::RenderingStarted { job_id, start_time, } => {
-                        println!("ConsoleResultReporter: job {:?} started at {:?}", job_id, start_time);
-                    },
-                    RenderEvent::ImageInfo { scene_name, width, height } => {
-                        println!("ConsoleResultReporter: scene: {}", scene_name);
-                        println!("ConsoleResultReporter: image {} x {} pixels",
-                                 width, height);
-                    },
-                    RenderEvent::RowsReady(unit_result) => {
-                        println!("ConsoleResultReporter: image fragment done, {} rows",
-                                 unit_result.work_unit.row_end - unit_result.work_unit.row_start + 1);
-                    },
-                    RenderEvent::RenderingFinished { end_time, } => {
-                        println!("ConsoleResultReporter: rendering finished at {:?}", end_time);
-                    }
-                }
-            }
-        }).unwrap();
-
-        Self {
-            sender: s,
-        }
-    }
-
-    pub fn sender(&self) -> Sender<Option<RenderEvent>> {
-        self.sender.clone()
-    }
+// Optional extras for an image builder beyond writing the finished
+// image.
+#[derive(Default)]
+pub struct ImageBuilderOptions {
+    // Write checkpoints of the job, and start from the checkpointed
+    // image if the job is being resumed.
+    pub checkpointer: Option<Checkpointer>,
+    // Write the image rendered so far to the output file this often,
+    // with the unrendered parts marked.
+    pub partial_interval: Option<Duration>,
 }
 
 pub struct ImageBuilder {
@@ -282,14 +549,21 @@ pub struct ImageBuilder {
 }
 
 impl ImageBuilder {
-    pub fn new() -> Self {
+    pub fn new(output: ImageOutput) -> Self {
+        Self::with_options(output, ImageBuilderOptions::default())
+    }
+
+    pub fn with_options(output: ImageOutput, options: ImageBuilderOptions) -> Self {
+        let mut checkpointer = options.checkpointer;
+        let partial_interval = options.partial_interval;
+        let mut last_partial_write = Instant::now();
         let (s, r): (Sender<Option<RenderEvent>>, Receiver<Option<RenderEvent>>) = unbounded();
         let img_ref = Arc::new(Mutex::new(None));
         let img_ref_thread = img_ref.clone();
 
         let thread_handle = thread::Builder::new().name("ImageBuilder".to_string()).spawn(move || {
-            let (scene_name, width, height) = match r.recv() {
-                Ok(Some(RenderEvent::ImageInfo { scene_name, width, height } )) => (scene_name, width, height),
+            let (width, height) = match r.recv() {
+                Ok(Some(RenderEvent::ImageInfo { width, height, .. } )) => (width, height),
                 _ => {
                     d_println(format!("ImageBuilder: got unexpected message"));
                     return;
@@ -299,7 +573,12 @@ impl ImageBuilder {
             d_println(format!("ImageBuilder: image {} x {} pixels", width, height));
 
             let start_time = match r.recv() {
-                Ok(Some(RenderEvent::RenderingStarted { start_time, .. })) => start_time,
+                Ok(Some(RenderEvent::RenderingStarted { job_id, start_time })) => {
+                    if let Some(c) = checkpointer.as_mut() {
+                        c.job_started(job_id);
+                    }
+                    start_time
+                },
                 _ => {
                     d_println(format!("ImageBuilder: got unexpected message when expecting render start message"));
                     return;
@@ -308,30 +587,53 @@ impl ImageBuilder {
 
             {
                 let mut img = img_ref_thread.lock().unwrap();
-                *img = Some(Image::new(width, height));
+                *img = Some(checkpointer.as_ref().and_then(|c| c.initial_image())
+                            .unwrap_or_else(|| Image::new(width, height)));
             }
 
             while let Ok(Some(result)) = r.recv() {
                 match result {
                     RenderEvent::RowsReady(unit_result) => {
-                        d_println(format!("ImageBuilder: image fragment done, {} rows",
-                                          unit_result.work_unit.row_end - unit_result.work_unit.row_start + 1));
+                        d_println(format!("ImageBuilder: image tile done, {} x {} pixels",
+                                          unit_result.work_unit.width(), unit_result.work_unit.height()));
 
                         let mut opt = img_ref_thread.lock().unwrap();
                         let img = opt.as_mut().unwrap();
-                        for (i, row) in unit_result.rows.into_iter().enumerate() {
-                            img.set_row(i + unit_result.work_unit.row_start, row);
+                        for (i, (row, counts)) in unit_result.rows.iter().zip(&unit_result.sample_counts).enumerate() {
+                            img.accumulate_row(i + unit_result.work_unit.row_start,
+                                               unit_result.work_unit.col_start, row, counts);
+                        }
+
+                        if let Some(c) = checkpointer.as_mut() {
+                            c.unit_completed(unit_result.work_unit, img);
+                        }
+
+                        // Write partial images from a copy, so that the
+                        // image is not locked while writing
+                        if partial_interval.map_or(false, |i| last_partial_write.elapsed() >= i) {
+                            let partial = img.marking_unrendered();
+                            drop(opt);
+                            match output.write(&partial) {
+                                Ok(()) => d_println(format!("ImageBuilder: wrote partial image {}", output.path)),
+                                Err(e) => println!("Could not write partial image {}: {}", output.path, e),
+                            }
+                            last_partial_write = Instant::now();
                         }
                     },
                     RenderEvent::RenderingFinished { end_time, } => {
                         println!("rendering finished, total time {:?}", end_time.duration_since(start_time));
                         d_println(format!("ImageBuilder: rendering finished, total time {:?}",
                                           end_time.duration_since(start_time)));
-                        let filename = scene_name.clone() + ".ppm";
-                        let mut output_file = File::create(filename).unwrap();
                         let mut opt = img_ref_thread.lock().unwrap();
                         let img = opt.as_mut().unwrap();
-                        img.write(&mut output_file);
+                        match output.write(img) {
+                            Ok(()) => println!("Wrote {}", output.path),
+                            Err(e) => println!("Could not write {}: {}", output.path, e),
+                        }
+
+                        if let Some(c) = checkpointer.as_mut() {
+                            c.job_finished(img);
+                        }
                     },
                     _ => {
                         d_println(format!("ImageBuilder: got unexpected message"));
@@ -361,33 +663,3 @@ impl ImageBuilder {
         self.thread_handle.join().ok();
     }
 }
-
-struct CancellableIterator<T: Iterator> {
-    items: T,
-    cancelled: bool,
-}
-
-impl<T: Iterator> CancellableIterator<T> {
-    pub fn new(items: T) -> Self {
-        Self {
-            items,
-            cancelled: false,
-        }
-    }
-
-    pub fn cancel(&mut self) {
-        self.cancelled = true;
-    }
-}
-
-impl<T: Iterator> Iterator for CancellableIterator<T> {
-    type Item = T::Item;
-
-    fn next(&mut self) -> Option<T::Item> {
-        if self.cancelled {
-            None
-        } else {
-            self.items.next()
-        }
-    }
-}
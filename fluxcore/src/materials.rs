@@ -1,13 +1,25 @@
 
+use std::sync::Arc;
+
 use crate::brdf::*;
 use crate::common::*;
 use crate::color::Color;
 use crate::scene::Scene;
 use crate::sampling::MasterSampleSets;
+use crate::lights::AreaLight;
+use crate::texture::Texture;
 
 pub trait Material: Sync + Send {
     fn path_shade(&self, scene: &Scene, hit: &Hit, samples: &MasterSampleSets,
                   set_index: usize, sample_index: usize) -> Color;
+
+    // The light that this material emits for, if it belongs to a shape
+    // that the scene samples directly. Rays that hit such a light after
+    // a BRDF-sampled bounce have already been partly accounted for by
+    // light sampling, so their contribution needs to be weighted.
+    fn area_light(&self) -> Option<&AreaLight> {
+        None
+    }
 }
 
 pub struct Matte {
@@ -20,7 +32,7 @@ impl Material for Matte {
                   set_index: usize, sample_index: usize) -> Color {
         let wo = -1.0 * hit.ray.direction;
         let hemi_sample = &samples.hemi_sets[set_index][hit.depth - 1][sample_index];
-        let sq_sample = &samples.pixel_sets[set_index][sample_index];
+        let sq_sample = &samples.brdf_sets[set_index][hit.depth - 1][sample_index];
         let (wi, pdf, f) = self.diffuse_brdf.sample_f(hit, &wo, &hemi_sample, &sq_sample);
         let ndotwi = hit.normal.dot(&wi);
         let reflected_ray = Ray {
@@ -28,25 +40,32 @@ impl Material for Matte {
             direction: wi,
         };
 
-        f * scene.shade(&reflected_ray, hit.depth + 1, &samples, set_index, sample_index) *
+        let direct = scene.sample_direct(hit, &self.diffuse_brdf, &wo, &samples, set_index, sample_index);
+
+        direct + f * scene.shade_bounce(&reflected_ray, hit.depth + 1, pdf, &samples, set_index, sample_index) *
             (ndotwi / pdf)
     }
 }
 
 pub struct Emissive {
-    pub color: Color,
+    pub color: Box<dyn Texture>,
     pub power: f64,
+    pub light: Option<Arc<AreaLight>>,
 }
 
 impl Material for Emissive {
     fn path_shade(&self, _scene: &Scene, hit: &Hit, _samples: &MasterSampleSets,
                   _set_index: usize, _sample_index: usize) -> Color {
         if (hit.normal * -1.0).dot(&hit.ray.direction) > 0.0 {
-            self.color * self.power
+            self.color.at_hit(hit) * self.power
         } else {
             Color::black()
         }
     }
+
+    fn area_light(&self) -> Option<&AreaLight> {
+        self.light.as_ref().map(|l| l.as_ref())
+    }
 }
 
 pub struct Reflective {
@@ -58,7 +77,7 @@ impl Material for Reflective {
                   set_index: usize, sample_index: usize) -> Color {
         let wo = hit.ray.direction * -1.0;
         let hemi_sample = &samples.hemi_sets[set_index][hit.depth - 1][sample_index];
-        let sq_sample = &samples.pixel_sets[set_index][sample_index];
+        let sq_sample = &samples.brdf_sets[set_index][hit.depth - 1][sample_index];
         let (wi, pdf, fr) = self.reflective_brdf.sample_f(hit, &wo, &hemi_sample, &sq_sample);
 
         let reflected_ray = Ray {
@@ -66,7 +85,87 @@ impl Material for Reflective {
             direction: wi,
         };
 
-        fr * scene.shade(&reflected_ray, hit.depth + 1, &samples, set_index, sample_index) *
-            (hit.normal.dot(&wi) / pdf)
+        if self.reflective_brdf.is_delta() {
+            return fr * scene.shade(&reflected_ray, hit.depth + 1, &samples, set_index, sample_index) *
+                (hit.normal.dot(&wi) / pdf);
+        }
+
+        let direct = scene.sample_direct(hit, self.reflective_brdf.as_ref(), &wo, &samples, set_index, sample_index);
+        let ndotwi = hit.normal.dot(&wi);
+
+        if ndotwi <= 0.0 || pdf <= 0.0 {
+            direct
+        } else {
+            direct + fr * scene.shade_bounce(&reflected_ray, hit.depth + 1, pdf, &samples, set_index, sample_index) *
+                (ndotwi / pdf)
+        }
+    }
+}
+
+// A smooth transparent material such as glass or water. At each hit the
+// ray is either reflected or refracted, with the choice made in
+// proportion to the Fresnel reflectance so that no weighting is needed.
+// If an absorption color is given, light travelling through the
+// interior is attenuated to that color per unit of distance.
+pub struct Dielectric {
+    pub ior: f64,
+    pub reflective_brdf: PerfectSpecular,
+    pub transmissive_btdf: PerfectTransmitter,
+    pub absorption_color: Option<Box<dyn Texture>>,
+}
+
+impl Material for Dielectric {
+    fn path_shade(&self, scene: &Scene, hit: &Hit, samples: &MasterSampleSets,
+                  set_index: usize, sample_index: usize) -> Color {
+        let wo = hit.ray.direction * -1.0;
+        let cos_wo = hit.normal.dot(&wo);
+        let inside = cos_wo < 0.0;
+        let kr = if inside {
+            fresnel_dielectric(-cos_wo, self.ior, 1.0)
+        } else {
+            fresnel_dielectric(cos_wo, 1.0, self.ior)
+        };
+
+        let hemi_sample = &samples.hemi_sets[set_index][hit.depth - 1][sample_index];
+        let sq_sample = &samples.brdf_sets[set_index][hit.depth - 1][sample_index];
+
+        // Light sampling is pointless for a smooth surface, so the light
+        // sample for this depth is free to make the choice with.
+        let choice = samples.light_sets[set_index][hit.depth - 1][sample_index].x;
+        let transmitted = if choice < kr {
+            None
+        } else {
+            self.transmissive_btdf.sample_f(hit, &wo)
+        };
+
+        let c = match transmitted {
+            Some((wt, ft)) => {
+                let transmitted_ray = Ray {
+                    origin: hit.local_hit_point,
+                    direction: wt,
+                };
+                ft * scene.shade(&transmitted_ray, hit.depth + 1, &samples, set_index, sample_index)
+            },
+            None => {
+                // Reflection, including total internal reflection. The
+                // mirror direction is the same on either side of the
+                // surface, and the pdf PerfectSpecular reports cancels
+                // the cosine term for us.
+                let (wi, pdf, fr) = self.reflective_brdf.sample_f(hit, &wo, &hemi_sample, &sq_sample);
+                let reflected_ray = Ray {
+                    origin: hit.local_hit_point,
+                    direction: wi,
+                };
+                fr * scene.shade(&reflected_ray, hit.depth + 1, &samples, set_index, sample_index) *
+                    (hit.normal.dot(&wi) / pdf)
+            },
+        };
+
+        // A ray that hit the inside of the surface travelled through the
+        // interior to get here.
+        match &self.absorption_color {
+            Some(a) if inside => c * a.at_hit(hit).powf(hit.distance),
+            _ => c,
+        }
     }
 }
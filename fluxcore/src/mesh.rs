@@ -0,0 +1,283 @@
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use nalgebra::{Vector3, Point3, Point2};
+
+use crate::constants::T_MIN;
+use crate::common::*;
+use crate::materials::Material;
+use crate::shapes::BoundingBox;
+use crate::bvh::{Bvh, Bounded};
+
+// The geometry of a triangle mesh as loaded from a Wavefront OBJ file.
+// This is plain data so that it can travel to network nodes as part of
+// the SceneData.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MeshGeometry {
+    pub positions: Vec<Point3<f64>>,
+    pub normals: Vec<Vector3<f64>>,
+    pub uvs: Vec<Point2<f64>>,
+    pub faces: Vec<MeshFace>,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MeshFace {
+    pub vertices: [MeshVertex; 3],
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MeshVertex {
+    pub position: usize,
+    pub uv: Option<usize>,
+    pub normal: Option<usize>,
+}
+
+fn parse_error(path: &Path, line_num: usize, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("{}:{}: {}", path.display(), line_num, msg))
+}
+
+fn parse_floats(path: &Path, line_num: usize, fields: &[&str], count: usize) -> io::Result<Vec<f64>> {
+    if fields.len() < count {
+        return Err(parse_error(path, line_num, format!("expected {} values, got {}", count, fields.len())));
+    }
+
+    fields[0..count].iter().map(|f| {
+        match f64::from_str(f) {
+            Ok(v) if v.is_finite() => Ok(v),
+            Ok(_) => Err(parse_error(path, line_num, format!("invalid number '{}': not finite", f))),
+            Err(e) => Err(parse_error(path, line_num, format!("invalid number '{}': {}", f, e))),
+        }
+    }).collect()
+}
+
+// OBJ indices are 1-based, and negative indices count backwards from
+// the most recently defined element.
+fn resolve_index(path: &Path, line_num: usize, raw: &str, len: usize) -> io::Result<usize> {
+    let i = i64::from_str(raw)
+        .map_err(|e| parse_error(path, line_num, format!("invalid index '{}': {}", raw, e)))?;
+
+    let resolved = if i > 0 { i - 1 } else { len as i64 + i };
+
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        Err(parse_error(path, line_num, format!("index {} out of range", i)))
+    } else {
+        Ok(resolved as usize)
+    }
+}
+
+fn parse_vertex(path: &Path, line_num: usize, raw: &str, g: &MeshGeometry) -> io::Result<MeshVertex> {
+    let parts: Vec<&str> = raw.split('/').collect();
+
+    let position = resolve_index(path, line_num, parts[0], g.positions.len())?;
+    let uv = match parts.get(1) {
+        Some(s) if !s.is_empty() => Some(resolve_index(path, line_num, s, g.uvs.len())?),
+        _ => None,
+    };
+    let normal = match parts.get(2) {
+        Some(s) if !s.is_empty() => Some(resolve_index(path, line_num, s, g.normals.len())?),
+        _ => None,
+    };
+
+    Ok(MeshVertex { position, uv, normal })
+}
+
+impl MeshGeometry {
+    // Load the positions, normals, texture coordinates and faces of a
+    // Wavefront OBJ file. Polygons with more than three vertices are
+    // triangulated as fans; all other statements are ignored.
+    pub fn load_obj(path: &Path) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut g = MeshGeometry {
+            positions: vec![],
+            normals: vec![],
+            uvs: vec![],
+            faces: vec![],
+        };
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line_num = i + 1;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            match fields.first() {
+                Some(&"v") => {
+                    let v = parse_floats(path, line_num, &fields[1..], 3)?;
+                    g.positions.push(Point3::new(v[0], v[1], v[2]));
+                },
+                Some(&"vn") => {
+                    let v = parse_floats(path, line_num, &fields[1..], 3)?;
+                    let n = Vector3::new(v[0], v[1], v[2]);
+                    if n.norm() == 0.0 {
+                        return Err(parse_error(path, line_num, "normal has zero length".to_string()));
+                    }
+                    g.normals.push(n.normalize());
+                },
+                Some(&"vt") => {
+                    let v = parse_floats(path, line_num, &fields[1..], 2)?;
+                    g.uvs.push(Point2::new(v[0], v[1]));
+                },
+                Some(&"f") => {
+                    if fields.len() < 4 {
+                        return Err(parse_error(path, line_num, "face has fewer than three vertices".to_string()));
+                    }
+
+                    let vs = fields[1..].iter()
+                        .map(|raw| parse_vertex(path, line_num, raw, &g))
+                        .collect::<io::Result<Vec<MeshVertex>>>()?;
+
+                    for j in 1..vs.len() - 1 {
+                        g.faces.push(MeshFace { vertices: [vs[0], vs[j], vs[j + 1]] });
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        if g.faces.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{}: mesh has no faces", path.display())));
+        }
+
+        Ok(g)
+    }
+}
+
+pub struct Triangle {
+    pub p0: Point3<f64>,
+    pub p1: Point3<f64>,
+    pub p2: Point3<f64>,
+    pub normal: Vector3<f64>,
+    pub vertex_normals: Option<[Vector3<f64>; 3]>,
+    pub vertex_uvs: Option<[Point2<f64>; 3]>,
+}
+
+impl Triangle {
+    // Build the triangle for a face, or None if the face has no area and
+    // so can never be hit.
+    pub fn new(g: &MeshGeometry, f: &MeshFace) -> Option<Self> {
+        let p0 = g.positions[f.vertices[0].position];
+        let p1 = g.positions[f.vertices[1].position];
+        let p2 = g.positions[f.vertices[2].position];
+        let cross = (p1 - p0).cross(&(p2 - p0));
+        if cross.norm() == 0.0 {
+            return None;
+        }
+        let normal = cross.normalize();
+
+        // Only use smooth shading if every vertex of this face has a
+        // normal.
+        let vertex_normals = match (f.vertices[0].normal, f.vertices[1].normal, f.vertices[2].normal) {
+            (Some(n0), Some(n1), Some(n2)) => Some([g.normals[n0], g.normals[n1], g.normals[n2]]),
+            _ => None,
+        };
+
+        // Faces without texture coordinates use their barycentric
+        // coordinates instead.
+        let vertex_uvs = match (f.vertices[0].uv, f.vertices[1].uv, f.vertices[2].uv) {
+            (Some(t0), Some(t1), Some(t2)) => Some([g.uvs[t0], g.uvs[t1], g.uvs[t2]]),
+            _ => None,
+        };
+
+        Some(Self { p0, p1, p2, normal, vertex_normals, vertex_uvs })
+    }
+
+    // Moller-Trumbore intersection, returning the ray parameter and the
+    // shading normal and UV coordinates at the hit point.
+    pub fn hit(&self, r: &Ray) -> Option<(f64, Vector3<f64>, Point2<f64>)> {
+        let e1 = self.p1 - self.p0;
+        let e2 = self.p2 - self.p0;
+        let p = r.direction.cross(&e2);
+        let det = e1.dot(&p);
+
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = r.origin - self.p0;
+        let beta = s.dot(&p) * inv_det;
+        if beta < 0.0 || beta > 1.0 {
+            return None;
+        }
+
+        let q = s.cross(&e1);
+        let gamma = r.direction.dot(&q) * inv_det;
+        if gamma < 0.0 || beta + gamma > 1.0 {
+            return None;
+        }
+
+        let t = e2.dot(&q) * inv_det;
+        if t <= T_MIN {
+            return None;
+        }
+
+        let normal = match &self.vertex_normals {
+            None => self.normal,
+            Some(ns) => (ns[0] * (1.0 - beta - gamma) + ns[1] * beta + ns[2] * gamma).normalize(),
+        };
+
+        let uv = match &self.vertex_uvs {
+            None => Point2::new(beta, gamma),
+            Some(ts) => Point2::from(ts[0].coords * (1.0 - beta - gamma) + ts[1].coords * beta + ts[2].coords * gamma),
+        };
+
+        Some((t, normal, uv))
+    }
+}
+
+impl Bounded for Triangle {
+    fn bounds(&self) -> BoundingBox {
+        BoundingBox::from_points([self.p0, self.p1, self.p2].iter())
+    }
+}
+
+pub struct Mesh {
+    pub triangles: Bvh<Triangle>,
+    pub material: Box<dyn Material>,
+    pub bbox: BoundingBox,
+}
+
+impl Mesh {
+    pub fn new(g: &MeshGeometry, material: Box<dyn Material>) -> Self {
+        let triangles: Vec<Triangle> = g.faces.iter().filter_map(|f| Triangle::new(g, f)).collect();
+        let bbox = BoundingBox::from_points(g.positions.iter());
+
+        Self {
+            triangles: Bvh::new(triangles),
+            material,
+            bbox,
+        }
+    }
+}
+
+impl Intersectable for Mesh {
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(self.bbox)
+    }
+
+    fn hit<'a>(&'a self, r: &Ray, depth: usize) -> Option<Hit<'a>> {
+        let closest = self.triangles.hit(&r, |t| t.hit(&r).map(|(d, n, uv)| (d, (n, uv))));
+
+        closest.map(|(t, (normal, uv))| {
+            Hit {
+                ray: r.clone(),
+                depth,
+                distance: t,
+                normal,
+                uv,
+                local_hit_point: r.origin + t * r.direction,
+                material: self.material.as_ref(),
+            }
+        })
+    }
+}
@@ -0,0 +1,245 @@
+
+use std::fs::File;
+use std::io;
+use std::convert::TryFrom;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use rustls::pki_types::ServerName;
+
+// Settings for securing the connections a manager makes to nodes.
+#[derive(Clone)]
+#[derive(Default)]
+pub struct ClientSecurity {
+    // If set, connect with TLS and verify nodes against these roots.
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    // The shared secret for nodes that require authentication.
+    pub secret: Option<Vec<u8>>,
+}
+
+// Settings for securing the connections a node accepts.
+#[derive(Clone)]
+#[derive(Default)]
+pub struct ServerSecurity {
+    // If set, only accept TLS connections, presenting this certificate.
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    // If set, managers must prove that they know this secret.
+    pub secret: Option<Vec<u8>>,
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("TLS error: {}", e))
+}
+
+fn invalid_file(path: &str, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, msg))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_file(path, "no PEM certificates found".to_string()));
+    }
+    Ok(certs)
+}
+
+// TLS settings for a node, from PEM files holding its certificate chain
+// and private key.
+pub fn server_tls_config(cert_path: &str, key_path: &str) -> io::Result<Arc<rustls::ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid_file(key_path, "no PEM private key found".to_string()))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+
+    Ok(Arc::new(config))
+}
+
+// TLS settings for a manager, trusting nodes whose certificates are
+// signed by one of the certificates in the PEM file at ca_path.
+pub fn client_tls_config(ca_path: &str) -> io::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert).map_err(|e| invalid_file(ca_path, format!("{}", e)))?;
+    }
+
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+// Read a shared secret from a file, ignoring surrounding whitespace.
+pub fn load_secret(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    let secret = contents.trim();
+    if secret.is_empty() {
+        return Err(invalid_file(path, "the secret is empty".to_string()));
+    }
+    Ok(secret.as_bytes().to_vec())
+}
+
+// A TLS session and the socket it runs over.
+pub struct TlsState {
+    conn: Mutex<rustls::Connection>,
+    // Held while sending records, so that they reach the socket in the
+    // order the session produced them. Take it before the session lock.
+    sending: Mutex<()>,
+    socket: TcpStream,
+}
+
+impl TlsState {
+    // Send the records the session has ready, with the sending lock
+    // held. The session itself is only locked while the records are
+    // taken from it, so that the other side of the connection can still
+    // be read while this waits on the socket.
+    fn send_pending(&self) -> io::Result<()> {
+        let mut records = vec![];
+        {
+            let mut conn = self.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+        }
+        (&self.socket).write_all(&records)
+    }
+}
+
+// A connection between a manager and a node. Like TcpStream, it can be
+// cloned so that one thread reads while another writes; for TLS the
+// clones share the session, which is only locked while records are
+// processed, never while waiting on the socket. A reader only waits for
+// a writer if the session has records of its own to send in reply,
+// which does not happen once the handshake is done.
+pub enum NetStream {
+    Plain(TcpStream),
+    Tls(Arc<TlsState>),
+}
+
+impl NetStream {
+    pub fn plain(socket: TcpStream) -> Self {
+        NetStream::Plain(socket)
+    }
+
+    // Perform the TLS handshake as the client, verifying that the server
+    // has a certificate for host.
+    pub fn connect_tls(socket: TcpStream, config: &Arc<rustls::ClientConfig>, host: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid host name '{}': {}", host, e)))?;
+        let conn = rustls::ClientConnection::new(Arc::clone(config), name).map_err(tls_error)?;
+        Self::handshake(socket, conn.into())
+    }
+
+    // Perform the TLS handshake as the server.
+    pub fn accept_tls(socket: TcpStream, config: &Arc<rustls::ServerConfig>) -> io::Result<Self> {
+        let conn = rustls::ServerConnection::new(Arc::clone(config)).map_err(tls_error)?;
+        Self::handshake(socket, conn.into())
+    }
+
+    fn handshake(socket: TcpStream, conn: rustls::Connection) -> io::Result<Self> {
+        let mut conn = conn;
+        let mut sock = &socket;
+
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut sock)?;
+        }
+
+        Ok(NetStream::Tls(Arc::new(TlsState {
+            conn: Mutex::new(conn),
+            sending: Mutex::new(()),
+            socket,
+        })))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            NetStream::Plain(s) => Ok(NetStream::Plain(s.try_clone()?)),
+            NetStream::Tls(t) => Ok(NetStream::Tls(Arc::clone(t))),
+        }
+    }
+
+    // The underlying socket, for addresses and timeouts.
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            NetStream::Plain(s) => s,
+            NetStream::Tls(t) => &t.socket,
+        }
+    }
+}
+
+impl Read for NetStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match self {
+            NetStream::Plain(s) => return s.read(buf),
+            NetStream::Tls(t) => t,
+        };
+
+        loop {
+            {
+                let mut conn = tls.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let mut incoming = [0u8; 4096];
+            let n = (&tls.socket).read(&mut incoming)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let reply = {
+                let mut conn = tls.conn.lock().unwrap();
+                let mut records = &incoming[..n];
+                while !records.is_empty() {
+                    conn.read_tls(&mut records)?;
+                    conn.process_new_packets().map_err(tls_error)?;
+                }
+                conn.wants_write()
+            };
+            if reply {
+                let _sending = tls.sending.lock().unwrap();
+                tls.send_pending()?;
+            }
+        }
+    }
+}
+
+impl Write for NetStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tls = match self {
+            NetStream::Plain(s) => return s.write(buf),
+            NetStream::Tls(t) => t,
+        };
+
+        let _sending = tls.sending.lock().unwrap();
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = tls.conn.lock().unwrap().writer().write(rest)?;
+            rest = &rest[n..];
+            tls.send_pending()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NetStream::Plain(s) => s.flush(),
+            NetStream::Tls(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+
+use crossbeam::channel::{Sender, Receiver, RecvTimeoutError, unbounded};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::job::JobID;
+use crate::manager::{RenderEvent, WorkUnitResult};
+use crate::debug::d_println;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum ProgressFormat {
+    // One human-readable line per report.
+    Text,
+    // One JSON object per report, each on its own line.
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ProgressFormat::Text),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!("unknown progress format '{}', expected text or json", s)),
+        }
+    }
+}
+
+// A progress report as written in the JSON format. The event is
+// "progress" while the job runs and "finished" at the end.
+#[derive(Serialize)]
+struct ProgressData<'a> {
+    event: &'static str,
+    job: Option<JobID>,
+    scene: &'a str,
+    // The fraction of the job's work done, including any done before it
+    // was resumed. Jobs with unlimited passes have no end, so this and
+    // the ETA are then null.
+    fraction_done: Option<f64>,
+    work_units_done: usize,
+    elapsed_secs: f64,
+    eta_secs: Option<f64>,
+    samples_per_sec: f64,
+    workers: Vec<WorkerProgressData<'a>>,
+}
+
+#[derive(Serialize)]
+struct WorkerProgressData<'a> {
+    name: &'a str,
+    work_units_done: usize,
+    samples: usize,
+    samples_per_sec: f64,
+}
+
+struct WorkerProgress {
+    units_done: usize,
+    samples: usize,
+    last_result: Instant,
+}
+
+// What is known about a job's progress so far. Rates and the ETA are
+// based on the work done since this process started on the job.
+struct Progress {
+    job: Option<JobID>,
+    scene_name: String,
+    pixels_per_pass: usize,
+    passes: usize,
+    resumed_pixels: usize,
+    resumed_units: usize,
+    pixels_done: usize,
+    units_done: usize,
+    started: Option<Instant>,
+    workers: BTreeMap<String, WorkerProgress>,
+}
+
+impl Progress {
+    fn record(&mut self, r: &WorkUnitResult) {
+        let samples: usize = r.sample_counts.iter().flat_map(|row| row.iter()).sum();
+        self.pixels_done += r.work_unit.width() * r.work_unit.height();
+        self.units_done += 1;
+
+        let worker = self.workers.entry(r.worker.clone()).or_insert(WorkerProgress {
+            units_done: 0,
+            samples: 0,
+            last_result: Instant::now(),
+        });
+        worker.units_done += 1;
+        worker.samples += samples;
+        worker.last_result = Instant::now();
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started.map_or(Duration::from_secs(0), |s| s.elapsed())
+    }
+
+    fn fraction_done(&self) -> Option<f64> {
+        let total = self.pixels_per_pass * self.passes;
+        if total == 0 {
+            None
+        } else {
+            Some(((self.resumed_pixels + self.pixels_done) as f64 / total as f64).min(1.0))
+        }
+    }
+
+    // The time left at the rate the work has been going since the job
+    // started here.
+    fn eta(&self) -> Option<Duration> {
+        let total = self.pixels_per_pass * self.passes;
+        if total == 0 || self.pixels_done == 0 {
+            return None;
+        }
+
+        let remaining = total.saturating_sub(self.resumed_pixels + self.pixels_done);
+        let secs = self.elapsed().as_secs_f64() * remaining as f64 / self.pixels_done as f64;
+        Some(Duration::from_millis((secs * 1000.0) as u64))
+    }
+
+    // A worker's rate over the time from the start of the job to its
+    // latest result, so that workers that have run out of work are not
+    // penalized for it.
+    fn worker_rate(&self, w: &WorkerProgress) -> f64 {
+        let secs = match self.started {
+            None => 0.0,
+            Some(s) => w.last_result.duration_since(s).as_secs_f64(),
+        };
+        if secs > 0.0 { w.samples as f64 / secs } else { 0.0 }
+    }
+
+    fn total_rate(&self) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        let samples: usize = self.workers.values().map(|w| w.samples).sum();
+        if secs > 0.0 { samples as f64 / secs } else { 0.0 }
+    }
+
+    fn text_report(&self, finished: bool) -> String {
+        let workers: Vec<String> = self.workers.iter().map(|(name, w)| {
+            format!("{} {}", name, format_rate(self.worker_rate(w)))
+        }).collect();
+        let units = self.resumed_units + self.units_done;
+
+        let done = if finished {
+            format!("Finished {} work units in {}", units, format_duration(self.elapsed()))
+        } else {
+            let fraction = match self.fraction_done() {
+                Some(f) => format!("{:.1}% done, ", f * 100.0),
+                None => String::new(),
+            };
+            let eta = match self.eta() {
+                Some(eta) => format!(", ETA {}", format_duration(eta)),
+                None => String::new(),
+            };
+            format!("[{}] {}{} work units{}", format_duration(self.elapsed()), fraction, units, eta)
+        };
+
+        format!("{}; {} samples/s ({})", done, format_rate(self.total_rate()), workers.join(", "))
+    }
+
+    fn json_report(&self, finished: bool) -> String {
+        let data = ProgressData {
+            event: if finished { "finished" } else { "progress" },
+            job: self.job,
+            scene: &self.scene_name,
+            fraction_done: self.fraction_done(),
+            work_units_done: self.resumed_units + self.units_done,
+            elapsed_secs: self.elapsed().as_secs_f64(),
+            eta_secs: if finished { Some(0.0) } else { self.eta().map(|d| d.as_secs_f64()) },
+            samples_per_sec: self.total_rate(),
+            workers: self.workers.iter().map(|(name, w)| WorkerProgressData {
+                name,
+                work_units_done: w.units_done,
+                samples: w.samples,
+                samples_per_sec: self.worker_rate(w),
+            }).collect(),
+        };
+
+        match serde_json::to_string(&data) {
+            Ok(s) => s,
+            Err(e) => panic!("ProgressData could not be serialized: {}", e),
+        }
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+fn format_rate(rate: f64) -> String {
+    if rate >= 1e9 {
+        format!("{:.2}G", rate / 1e9)
+    } else if rate >= 1e6 {
+        format!("{:.2}M", rate / 1e6)
+    } else if rate >= 1e3 {
+        format!("{:.2}K", rate / 1e3)
+    } else {
+        format!("{:.0}", rate)
+    }
+}
+
+// Reports a job's progress at regular intervals while passing its
+// events on to the next consumer, such as an image builder. Send the
+// job's results to this reporter's sender in place of the consumer's.
+pub struct ProgressReporter {
+    sender: Sender<Option<RenderEvent>>,
+    thread_handle: thread::JoinHandle<()>,
+}
+
+impl ProgressReporter {
+    // The passes are those of the job's configuration, and the pixels
+    // and units already done are those rendered before the job was
+    // resumed.
+    pub fn new(format: ProgressFormat, interval: Duration, passes: usize, resumed_pixels: usize, resumed_units: usize,
+               mut out: Box<dyn Write + Send>, next: Sender<Option<RenderEvent>>) -> Self {
+        let (s, r): (Sender<Option<RenderEvent>>, Receiver<Option<RenderEvent>>) = unbounded();
+
+        let mut progress = Progress {
+            job: None,
+            scene_name: String::new(),
+            pixels_per_pass: 0,
+            passes,
+            resumed_pixels,
+            resumed_units,
+            pixels_done: 0,
+            units_done: 0,
+            started: None,
+            workers: BTreeMap::new(),
+        };
+
+        let thread_handle = thread::Builder::new().name("ProgressReporter".to_string()).spawn(move || {
+            let mut report = |progress: &Progress, finished: bool| {
+                let line = match format {
+                    ProgressFormat::Text => progress.text_report(finished),
+                    ProgressFormat::Json => progress.json_report(finished),
+                };
+                if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+                    d_println(format!("ProgressReporter: could not write report: {}", e));
+                }
+            };
+            let mut next_report = Instant::now() + interval;
+
+            loop {
+                let timeout = next_report.saturating_duration_since(Instant::now());
+                let ev = match r.recv_timeout(timeout) {
+                    Ok(Some(ev)) => ev,
+                    Ok(None) | Err(RecvTimeoutError::Disconnected) => break,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if progress.started.is_some() {
+                            report(&progress, false);
+                        }
+                        next_report = Instant::now() + interval;
+                        continue;
+                    },
+                };
+
+                let finished = match &ev {
+                    RenderEvent::ImageInfo { scene_name, width, height } => {
+                        progress.scene_name = scene_name.clone();
+                        progress.pixels_per_pass = width * height;
+                        false
+                    },
+                    RenderEvent::RenderingStarted { job_id, .. } => {
+                        progress.job = Some(*job_id);
+                        progress.started = Some(Instant::now());
+                        false
+                    },
+                    RenderEvent::RowsReady(r) => {
+                        progress.record(r);
+                        false
+                    },
+                    RenderEvent::RenderingFinished { .. } => true,
+                };
+
+                if next.send(Some(ev)).is_err() {
+                    d_println(format!("ProgressReporter: could not pass on a render event"));
+                }
+
+                if finished {
+                    report(&progress, true);
+                }
+            }
+        }).unwrap();
+
+        Self {
+            sender: s,
+            thread_handle,
+        }
+    }
+
+    pub fn sender(&self) -> Sender<Option<RenderEvent>> {
+        self.sender.clone()
+    }
+
+    // Stop once the events sent so far have been passed on.
+    pub fn stop(self) {
+        self.sender.send(None).ok();
+        self.thread_handle.join().ok();
+    }
+}
@@ -0,0 +1,169 @@
+
+use std::io;
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor::to_writer;
+use serde_cbor::StreamDeserializer;
+use serde_cbor::de::IoRead;
+
+use crate::manager::WorkerInfo;
+use crate::net::NetStream;
+use crate::encoding::{PixelEncoding, WireEncoding};
+
+// The version of the manager/node protocol. Bump this whenever the
+// messages exchanged after the handshake change in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+// The version of flux that built this binary.
+pub const FLUX_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// The kinds of shapes, materials, textures and output formats this build
+// supports. These must match ShapeData::kind, MaterialData::kind,
+// TextureData::kind and ImageFormat.
+const SHAPE_KINDS: &[&str] = &["Sphere", "Plane", "Rectangle", "Mesh", "Instance"];
+const MATERIAL_KINDS: &[&str] = &["Matte", "Emissive", "Reflective", "GlossyReflective", "Dielectric", "Principled"];
+const TEXTURE_KINDS: &[&str] = &["Constant", "Image", "Checker", "Gradient", "Noise"];
+const OUTPUT_FORMATS: &[&str] = &["ppm", "png8", "png16", "exr"];
+
+// The size in bytes of the random challenge a node sends to managers
+// when it requires authentication.
+const AUTH_CHALLENGE_SIZE: usize = 32;
+
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+#[derive(Default)]
+pub struct Features {
+    pub shapes: Vec<String>,
+    pub materials: Vec<String>,
+    pub textures: Vec<String>,
+    pub output_formats: Vec<String>,
+}
+
+fn strings(vals: &[&str]) -> Vec<String> {
+    vals.iter().map(|v| v.to_string()).collect()
+}
+
+impl Features {
+    pub fn supported() -> Self {
+        Self {
+            shapes: strings(SHAPE_KINDS),
+            materials: strings(MATERIAL_KINDS),
+            textures: strings(TEXTURE_KINDS),
+            output_formats: strings(OUTPUT_FORMATS),
+        }
+    }
+
+    // The features in self that are missing from other.
+    pub fn missing_from(&self, other: &Features) -> Vec<String> {
+        let shapes = self.shapes.iter().filter(|s| !other.shapes.contains(s));
+        let materials = self.materials.iter().filter(|m| !other.materials.contains(m));
+        let textures = self.textures.iter().filter(|t| !other.textures.contains(t));
+        let formats = self.output_formats.iter().filter(|f| !other.output_formats.contains(f));
+        shapes.chain(materials).chain(textures).chain(formats).cloned().collect()
+    }
+}
+
+// The first message on a connection, sent by the node.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeHello {
+    pub protocol_version: u32,
+    pub flux_version: String,
+    pub hostname: String,
+    pub features: Features,
+    pub worker_info: WorkerInfo,
+    // Set when the node requires the manager to prove that it knows
+    // the node's shared secret.
+    pub auth_challenge: Option<Vec<u8>>,
+    // The ways the node can send results.
+    pub pixel_encodings: Vec<PixelEncoding>,
+    pub compression: bool,
+}
+
+impl NodeHello {
+    pub fn supports(&self, encoding: &WireEncoding) -> bool {
+        self.pixel_encodings.contains(&encoding.pixels) && (self.compression || !encoding.compress)
+    }
+}
+
+// The manager's reply to NodeHello.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManagerHello {
+    pub protocol_version: u32,
+    pub flux_version: String,
+    // The answer to the node's auth_challenge, if it sent one.
+    pub auth_response: Option<Vec<u8>>,
+    // How the node should send results.
+    pub wire_encoding: WireEncoding,
+}
+
+// The node's verdict on ManagerHello, which ends the handshake. After
+// Refused the node closes the connection.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum NodeGreeting {
+    Accepted,
+    Refused { reason: String },
+}
+
+impl ManagerHello {
+    pub fn new(auth_response: Option<Vec<u8>>, wire_encoding: WireEncoding) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            flux_version: FLUX_VERSION.to_string(),
+            auth_response,
+            wire_encoding,
+        }
+    }
+}
+
+// A fresh random challenge for a manager to answer.
+pub fn new_auth_challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; AUTH_CHALLENGE_SIZE];
+    SystemRandom::new().fill(&mut challenge).expect("could not generate an authentication challenge");
+    challenge
+}
+
+// The answer to a challenge: its HMAC-SHA256 under the shared secret.
+// The secret itself never crosses the connection.
+pub fn auth_response(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::sign(&key, challenge).as_ref().to_vec()
+}
+
+pub fn verify_auth_response(secret: &[u8], challenge: &[u8], response: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::verify(&key, challenge, response).is_ok()
+}
+
+// Check that the other end of a connection speaks our protocol.
+pub fn check_protocol_version(peer: &str, version: u32, flux_version: &str) -> Result<(), String> {
+    if version == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err(format!("{} speaks protocol version {} (flux {}), but this is flux {} with protocol version {}",
+                    peer, version, flux_version, FLUX_VERSION, PROTOCOL_VERSION))
+    }
+}
+
+pub fn write_message<T: Serialize>(stream: &mut NetStream, msg: &T) -> io::Result<()> {
+    to_writer(stream, msg).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+}
+
+// Read a single message. The deserializer does not read past the end of
+// the message, so other messages can be read from the stream afterwards.
+pub fn read_message<T: DeserializeOwned>(stream: &NetStream) -> io::Result<T> {
+    let mut de: StreamDeserializer<'_, IoRead<NetStream>, T> =
+        StreamDeserializer::new(IoRead::new(stream.try_clone()?));
+
+    match de.next() {
+        Some(Ok(msg)) => Ok(msg),
+        Some(Err(e)) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+    }
+}
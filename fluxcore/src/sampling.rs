@@ -7,6 +7,8 @@ pub struct MasterSampleSets {
     pub pixel_sets: Vec<Vec<samplers::UnitSquareSample>>,
     pub disc_sets: Vec<Vec<samplers::UnitDiscSample>>,
     pub hemi_sets: Vec<Vec<Vec<Vector3<f64>>>>,
+    pub light_sets: Vec<Vec<Vec<samplers::UnitSquareSample>>>,
+    pub brdf_sets: Vec<Vec<Vec<samplers::UnitSquareSample>>>,
 }
 
 impl MasterSampleSets {
@@ -20,11 +22,27 @@ impl MasterSampleSets {
                 samplers::to_poisson_disc(
                     sampler.grid_correlated_multi_jittered(sample_root))).collect(),
 
+            // Cosine-weighted, to match the density that Lambertian
+            // reports for its samples.
             hemi_sets: (0..num_sets).map(|_|
                 (0..max_depth).map(|_|
                     samplers::to_hemisphere(
                         sampler.grid_multi_jittered(sample_root),
-                        0.0)
+                        1.0)
+                    ).collect()
+                ).collect(),
+
+            light_sets: (0..num_sets).map(|_|
+                (0..max_depth).map(|_|
+                    sampler.grid_multi_jittered(sample_root)
+                    ).collect()
+                ).collect(),
+
+            // For BRDFs that choose a lobe or a microfacet normal, at
+            // each depth and independent of where the ray left the pixel.
+            brdf_sets: (0..num_sets).map(|_|
+                (0..max_depth).map(|_|
+                    sampler.grid_multi_jittered(sample_root)
                     ).collect()
                 ).collect(),
 
@@ -39,3 +57,16 @@ impl MasterSampleSets {
         sample_set_indexes
     }
 }
+
+// The power heuristic (with beta = 2) for weighting a sample taken from a
+// distribution with density f_pdf against another strategy with density
+// g_pdf when combining them with multiple importance sampling.
+pub fn power_heuristic(f_pdf: f64, g_pdf: f64) -> f64 {
+    let f2 = f_pdf * f_pdf;
+    let g2 = g_pdf * g_pdf;
+    if f2 + g2 == 0.0 {
+        0.0
+    } else {
+        f2 / (f2 + g2)
+    }
+}
@@ -1,13 +1,27 @@
 
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
 use nalgebra::{Vector3, Point3};
 
 use crate::color::Color;
-use crate::common::{Ray, Intersectable, Hit};
+use crate::tonemap::ToneMapping;
+use crate::common::{Ray, Intersectable, Hit, Sampleable};
 use crate::shapes::*;
+use crate::mesh::{Mesh, MeshGeometry};
+use crate::instance::Instance;
+use crate::bvh::{Bvh, Bounded};
 use crate::job::JobConfiguration;
+use crate::protocol::Features;
 use crate::materials::*;
 use crate::brdf::*;
-use crate::sampling::MasterSampleSets;
+use crate::sampling::{MasterSampleSets, power_heuristic};
+use crate::lights::AreaLight;
+use crate::texture::texture_from_data;
+use crate::constants::SHADOW_EPSILON;
+use crate::samplers::UnitSquareSample;
 
 #[derive(Clone)]
 #[derive(Serialize, Deserialize, Debug)]
@@ -43,9 +57,98 @@ pub struct SceneData {
     pub scene_name: String,
     pub output_settings: OutputSettings,
     pub background: Color,
+    // Shapes may all come from included libraries.
+    #[serde(default)]
     pub shapes: Vec<ShapeData>,
     pub camera_settings: CameraSettings,
     pub camera_data: CameraData,
+    // Materials that shapes can refer to by name.
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialData>,
+    // Shapes that instances can refer to by name. These are not part of
+    // the scene by themselves.
+    #[serde(default)]
+    pub prototypes: BTreeMap<String, ShapeData>,
+    // Scene library files to merge into the scene, relative to the
+    // scene file. These must be merged with add_library before the
+    // scene is rendered.
+    #[serde(default)]
+    pub include: Vec<String>,
+}
+
+// Materials and shapes that can be shared between scenes by including
+// the file they are in. Libraries can include other libraries.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SceneLibraryData {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialData>,
+    #[serde(default)]
+    pub prototypes: BTreeMap<String, ShapeData>,
+    #[serde(default)]
+    pub shapes: Vec<ShapeData>,
+}
+
+impl SceneLibraryData {
+    // Merge an included library into this one, keeping this library's
+    // materials and prototypes where both have one by the same name.
+    pub fn add_library(&mut self, lib: SceneLibraryData) {
+        merge_library(&mut self.materials, &mut self.prototypes, &mut self.shapes, lib);
+    }
+
+    // Read the library's OBJ files, resolving relative paths against
+    // the library file's directory.
+    pub fn load_meshes(&mut self, base_dir: &Path) -> io::Result<()> {
+        load_meshes(&mut self.shapes, &mut self.prototypes, base_dir)
+    }
+
+    // Read the library's texture images, resolving relative paths
+    // against the library file's directory.
+    pub fn load_textures(&mut self, base_dir: &Path) -> io::Result<()> {
+        load_textures(&mut self.materials, &mut self.shapes, &mut self.prototypes, base_dir)
+    }
+}
+
+fn merge_library(materials: &mut BTreeMap<String, MaterialData>, prototypes: &mut BTreeMap<String, ShapeData>,
+                 shapes: &mut Vec<ShapeData>, lib: SceneLibraryData) {
+    for (name, m) in lib.materials {
+        materials.entry(name).or_insert(m);
+    }
+    for (name, p) in lib.prototypes {
+        prototypes.entry(name).or_insert(p);
+    }
+    shapes.extend(lib.shapes);
+}
+
+fn load_meshes(shapes: &mut [ShapeData], prototypes: &mut BTreeMap<String, ShapeData>,
+               base_dir: &Path) -> io::Result<()> {
+    for shape in shapes.iter_mut().chain(prototypes.values_mut()) {
+        shape.load_meshes(base_dir)?;
+    }
+
+    Ok(())
+}
+
+fn load_textures(materials: &mut BTreeMap<String, MaterialData>, shapes: &mut [ShapeData],
+                 prototypes: &mut BTreeMap<String, ShapeData>, base_dir: &Path) -> io::Result<()> {
+    for m in materials.values_mut() {
+        load_material_textures(m, base_dir)?;
+    }
+    for shape in shapes.iter_mut().chain(prototypes.values_mut()) {
+        shape.load_textures(base_dir)?;
+    }
+
+    Ok(())
+}
+
+fn load_material_textures(m: &mut MaterialData, base_dir: &Path) -> io::Result<()> {
+    for t in m.textures_mut() {
+        t.load_images(base_dir)?;
+    }
+
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -63,21 +166,159 @@ pub struct OutputSettings {
     pub image_width: usize,
     pub image_height: usize,
     pub pixel_size: f64,
+    #[serde(default)]
+    pub tone_mapping: ToneMapping,
 }
 
-#[derive(Copy)]
 #[derive(Clone)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ShapeData {
     Sphere(SphereData),
     Plane(PlaneData),
+    Rectangle(RectangleData),
+    Mesh(MeshData),
+    Instance(InstanceData),
+}
+
+impl ShapeData {
+    // The name of this kind of shape, as used in scene files.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ShapeData::Sphere(_) => "Sphere",
+            ShapeData::Plane(_) => "Plane",
+            ShapeData::Rectangle(_) => "Rectangle",
+            ShapeData::Mesh(_) => "Mesh",
+            ShapeData::Instance(_) => "Instance",
+        }
+    }
+
+    // Instances have no material of their own; they use that of the
+    // shape they are an instance of.
+    pub fn material(&self) -> Option<&MaterialRef> {
+        match self {
+            ShapeData::Sphere(s) => Some(&s.material),
+            ShapeData::Plane(p) => Some(&p.material),
+            ShapeData::Rectangle(r) => Some(&r.material),
+            ShapeData::Mesh(m) => Some(&m.material),
+            ShapeData::Instance(_) => None,
+        }
+    }
+
+    fn load_meshes(&mut self, base_dir: &Path) -> io::Result<()> {
+        match self {
+            ShapeData::Mesh(m) => {
+                if m.geometry.is_none() {
+                    m.geometry = Some(MeshGeometry::load_obj(&base_dir.join(&m.file))?);
+                }
+            },
+            ShapeData::Instance(i) => {
+                if let Ref::Inline(shape) = &mut i.shape {
+                    shape.load_meshes(base_dir)?;
+                }
+            },
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    // Read the images of this shape's material if it has its own, and of
+    // the shape it is an instance of.
+    fn load_textures(&mut self, base_dir: &Path) -> io::Result<()> {
+        let material = match self {
+            ShapeData::Sphere(s) => &mut s.material,
+            ShapeData::Plane(p) => &mut p.material,
+            ShapeData::Rectangle(r) => &mut r.material,
+            ShapeData::Mesh(m) => &mut m.material,
+            ShapeData::Instance(i) => {
+                if let Ref::Inline(shape) = &mut i.shape {
+                    shape.load_textures(base_dir)?;
+                }
+                return Ok(());
+            },
+        };
+
+        match material {
+            Ref::Inline(m) => load_material_textures(m, base_dir),
+            Ref::Named(_) => Ok(()),
+        }
+    }
+
+    fn add_features(&self, materials: &BTreeMap<String, MaterialData>, f: &mut Features) {
+        let kind = self.kind().to_string();
+        if !f.shapes.contains(&kind) {
+            f.shapes.push(kind);
+        }
+
+        // Unknown material names are caught by validation
+        if let Some(m) = self.material().and_then(|m| m.resolve(materials)) {
+            let material = m.kind().to_string();
+            if !f.materials.contains(&material) {
+                f.materials.push(material);
+            }
+
+            let mut textures = m.textures();
+            while let Some(t) = textures.pop() {
+                let texture = t.kind().to_string();
+                if !f.textures.contains(&texture) {
+                    f.textures.push(texture);
+                }
+                textures.extend(t.parts());
+            }
+        }
+
+        // Named prototypes are covered by the scene's prototypes table
+        if let ShapeData::Instance(InstanceData { shape: Ref::Inline(shape), .. }) = self {
+            shape.add_features(materials, f);
+        }
+    }
+}
+
+impl SceneData {
+    // The shape and material kinds that a node needs to support in
+    // order to render this scene.
+    pub fn features(&self) -> Features {
+        let mut f = Features::default();
+
+        for shape in self.shapes.iter().chain(self.prototypes.values()) {
+            shape.add_features(&self.materials, &mut f);
+        }
+
+        f
+    }
+
+    // Read the OBJ file for every mesh in the scene, including those in
+    // prototypes and instances, resolving relative paths against
+    // base_dir. This must be done before the scene is handed to a
+    // RenderManager so that network nodes, which do not have access to
+    // our filesystem, receive the mesh geometry along with the rest of
+    // the scene.
+    pub fn load_meshes(&mut self, base_dir: &Path) -> io::Result<()> {
+        load_meshes(&mut self.shapes, &mut self.prototypes, base_dir)
+    }
+
+    // Read the image of every image texture in the scene, resolving
+    // relative paths against base_dir. Like meshes, these must be loaded
+    // before the scene is handed to a RenderManager.
+    pub fn load_textures(&mut self, base_dir: &Path) -> io::Result<()> {
+        load_textures(&mut self.materials, &mut self.shapes, &mut self.prototypes, base_dir)
+    }
+
+    // Merge an included library into the scene. The scene's own
+    // materials and prototypes take precedence over the library's, and
+    // the library's shapes are added after the scene's.
+    pub fn add_library(&mut self, lib: SceneLibraryData) {
+        merge_library(&mut self.materials, &mut self.prototypes, &mut self.shapes, lib);
+    }
 }
 
 pub struct Scene {
     pub scene_name: String,
     pub output_settings: OutputSettings,
     pub background: Color,
-    pub shapes: Vec<Box<dyn Intersectable>>,
+    pub bounded_shapes: Bvh<Box<dyn Intersectable>>,
+    pub unbounded_shapes: Vec<Box<dyn Intersectable>>,
+    pub lights: Vec<Arc<AreaLight>>,
     pub camera_settings: CameraSettings,
     pub camera_basis: CameraBasis,
     pub camera_data: CameraData,
@@ -88,24 +329,54 @@ pub fn material_from_data(d: &MaterialData) -> Box<dyn Material> {
     match d {
         MaterialData::Emissive(e) => {
             Box::new(Emissive {
-                color: e.color,
+                color: texture_from_data(&e.color),
                 power: e.power,
+                light: None,
             })
         },
         MaterialData::Reflective(p) => {
             Box::new(Reflective {
                 reflective_brdf: Box::new(PerfectSpecular {
                     kr: p.reflect_amount,
-                    cr: p.reflect_color,
+                    cr: texture_from_data(&p.reflect_color),
                 }),
             })
         },
         MaterialData::GlossyReflective(p) => {
             Box::new(Reflective {
-                reflective_brdf: Box::new(GlossySpecular {
+                reflective_brdf: Box::new(Microfacet {
                     ks: p.reflect_amount,
-                    cs: p.reflect_color,
-                    exp: p.reflect_exponent,
+                    base_color: texture_from_data(&p.reflect_color),
+                    metallic: 1.0,
+                    alpha: alpha_from_phong_exponent(p.reflect_exponent),
+                    ior: 1.0,
+                    conductor: None,
+                }),
+            })
+        },
+        MaterialData::Dielectric(d) => {
+            Box::new(Dielectric {
+                ior: d.ior,
+                reflective_brdf: PerfectSpecular {
+                    kr: 1.0,
+                    cr: Box::new(Color::white()),
+                },
+                transmissive_btdf: PerfectTransmitter {
+                    kt: 1.0,
+                    ior: d.ior,
+                },
+                absorption_color: d.absorption_color.as_ref().map(texture_from_data),
+            })
+        },
+        MaterialData::Principled(p) => {
+            Box::new(Reflective {
+                reflective_brdf: Box::new(Microfacet {
+                    ks: 1.0,
+                    base_color: texture_from_data(&p.base_color),
+                    metallic: p.metallic,
+                    alpha: alpha_from_roughness(p.roughness),
+                    ior: p.ior,
+                    conductor: p.conductor.as_ref().map(|c| c.ior()),
                 }),
             })
         },
@@ -113,39 +384,149 @@ pub fn material_from_data(d: &MaterialData) -> Box<dyn Material> {
             Box::new(Matte {
                 ambient_brdf: Lambertian {
                     diffuse_coefficient: m.diffuse_coefficient,
-                    diffuse_color: m.ambient_color,
+                    diffuse_color: texture_from_data(&m.ambient_color),
                 },
                 diffuse_brdf: Lambertian {
                     diffuse_coefficient: m.diffuse_coefficient,
-                    diffuse_color: m.diffuse_color,
+                    diffuse_color: texture_from_data(&m.diffuse_color),
                 }
             })
         },
     }
 }
 
+// Build the material for a shape that can be sampled. If the material is
+// emissive, the shape is also registered as a light so that other
+// surfaces can sample it directly.
+fn light_material<F>(d: &MaterialData, lights: &mut Vec<Arc<AreaLight>>, shape: F) -> Box<dyn Material>
+    where F: FnOnce() -> Box<dyn Sampleable>
+{
+    match d {
+        MaterialData::Emissive(e) => {
+            let light = AreaLight::new(shape(), e);
+            lights.push(light.clone());
+            Box::new(Emissive {
+                color: texture_from_data(&e.color),
+                power: e.power,
+                light: Some(light),
+            })
+        },
+        _ => material_from_data(d),
+    }
+}
+
+impl Bounded for Box<dyn Intersectable> {
+    fn bounds(&self) -> BoundingBox {
+        self.bounding_box().expect("Bounded::bounds called on an unbounded shape")
+    }
+}
+
+// Builds the shapes of a scene from their data, building each prototype
+// only once no matter how many instances share it.
+struct ShapeBuilder<'a> {
+    materials: &'a BTreeMap<String, MaterialData>,
+    prototypes: &'a BTreeMap<String, ShapeData>,
+    built_prototypes: HashMap<String, Arc<dyn Intersectable>>,
+    lights: Vec<Arc<AreaLight>>,
+}
+
+impl<'a> ShapeBuilder<'a> {
+    fn material(&self, r: &MaterialRef) -> MaterialData {
+        match r.resolve(self.materials) {
+            Some(m) => m.clone(),
+            None => panic!("ShapeBuilder: no material named {:?}", r),
+        }
+    }
+
+    // Emissive shapes become lights that other surfaces sample directly,
+    // except inside instances: their emission is still found by rays
+    // that hit them, just less efficiently.
+    fn sampleable_material<F>(&mut self, d: &MaterialData, instanced: bool, shape: F) -> Box<dyn Material>
+        where F: FnOnce() -> Box<dyn Sampleable>
+    {
+        if instanced {
+            material_from_data(d)
+        } else {
+            light_material(d, &mut self.lights, shape)
+        }
+    }
+
+    fn prototype(&mut self, name: &str) -> Arc<dyn Intersectable> {
+        if let Some(p) = self.built_prototypes.get(name) {
+            return p.clone();
+        }
+
+        let data = match self.prototypes.get(name) {
+            Some(d) => d.clone(),
+            None => panic!("ShapeBuilder: no prototype named {}", name),
+        };
+        let p: Arc<dyn Intersectable> = Arc::from(self.build(data, true));
+        self.built_prototypes.insert(name.to_string(), p.clone());
+        p
+    }
+
+    fn build(&mut self, sd: ShapeData, instanced: bool) -> Box<dyn Intersectable> {
+        match sd {
+            ShapeData::Sphere(s) => {
+                let md = self.material(&s.material);
+                let m = self.sampleable_material(&md, instanced, || {
+                    Box::new(Sphere::new(s.clone(), material_from_data(&md)))
+                });
+                Box::new(Sphere::new(s, m))
+            },
+            ShapeData::Plane(p) => {
+                let m = material_from_data(&self.material(&p.material));
+                Box::new(Plane::new(p, m))
+            },
+            ShapeData::Rectangle(r) => {
+                let md = self.material(&r.material);
+                let m = self.sampleable_material(&md, instanced, || {
+                    Box::new(Rectangle::new(r.clone(), material_from_data(&md)))
+                });
+                Box::new(Rectangle::new(r, m))
+            },
+            ShapeData::Mesh(md) => {
+                let m = material_from_data(&self.material(&md.material));
+                let g = match md.geometry {
+                    Some(g) => g,
+                    None => panic!("Mesh geometry for '{}' was not loaded", md.file),
+                };
+                Box::new(Mesh::new(&g, m))
+            },
+            ShapeData::Instance(InstanceData { shape, translate, rotate, scale }) => {
+                let shape = match shape {
+                    Ref::Named(name) => self.prototype(&name),
+                    Ref::Inline(shape) => Arc::from(self.build(*shape, true)),
+                };
+                Box::new(Instance::new(shape, translate, rotate, scale))
+            },
+        }
+    }
+}
+
 impl Scene {
     pub fn from_data(sd: SceneData, config: JobConfiguration) -> Scene {
-        let shapes: Vec<Box<dyn Intersectable>> = sd.shapes.into_iter().map(|sd| {
-            match sd {
-                ShapeData::Sphere(s) => {
-                    let m = material_from_data(&s.material);
-                    let b: Box<dyn Intersectable> = Box::new(Sphere::new(s, m));
-                    b
-                },
-                ShapeData::Plane(p) => {
-                    let m = material_from_data(&p.material);
-                    let b: Box<dyn Intersectable> = Box::new(Plane { data: p, material: m });
-                    b
-                },
-            }
-        }).collect();
+        let mut builder = ShapeBuilder {
+            materials: &sd.materials,
+            prototypes: &sd.prototypes,
+            built_prototypes: HashMap::new(),
+            lights: vec![],
+        };
+        let shapes: Vec<Box<dyn Intersectable>> = sd.shapes.into_iter().map(|s| builder.build(s, false)).collect();
+        let lights = builder.lights;
+
+        // Shapes with finite bounds go into the BVH; the rest (such as
+        // planes) have to be tested against every ray.
+        let (bounded, unbounded): (Vec<Box<dyn Intersectable>>, Vec<Box<dyn Intersectable>>) =
+            shapes.into_iter().partition(|s| s.bounding_box().is_some());
 
         Scene {
             output_settings: sd.output_settings,
             background: sd.background,
             scene_name: sd.scene_name,
-            shapes,
+            bounded_shapes: Bvh::new(bounded),
+            unbounded_shapes: unbounded,
+            lights,
             camera_basis: CameraBasis::new(&sd.camera_settings),
             camera_settings: sd.camera_settings,
             camera_data: sd.camera_data,
@@ -154,11 +535,86 @@ impl Scene {
     }
 
     fn hit(&self, r: &Ray, depth: usize) -> Option<Hit> {
-        self.shapes.iter()
+        let bounded_hit = self.bounded_shapes.hit(&r, |o| o.hit(&r, depth).map(|h| (h.distance, h)))
+            .map(|(_, h)| h);
+
+        self.unbounded_shapes.iter()
             .filter_map(|o| o.hit(&r, depth))
+            .chain(bounded_hit)
             .min_by(Hit::compare)
     }
 
+    // Shade a ray whose direction was chosen by sampling a BRDF with
+    // density brdf_pdf. If it hits a light that could also have been
+    // found by sample_direct, its emission is weighted accordingly.
+    pub fn shade_bounce(&self, r: &Ray, depth: usize, brdf_pdf: f64, samples: &MasterSampleSets,
+                        set_index: usize, sample_index: usize) -> Color {
+        if depth > self.job_config.max_trace_depth {
+            Color::black()
+        } else {
+            match self.hit(&r, depth) {
+                None => self.background,
+                Some(h) => {
+                    let c = h.material.path_shade(&self, &h, &samples, set_index, sample_index);
+                    match h.material.area_light() {
+                        None => c,
+                        Some(light) => {
+                            let light_pdf = light.pdf(&r.origin, &h.local_hit_point) /
+                                self.lights.len() as f64;
+                            c * power_heuristic(brdf_pdf, light_pdf)
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+    // Estimate the light arriving at a hit directly from the scene's
+    // lights by sampling a point on one of them, weighted for
+    // combination with BRDF sampling via shade_bounce.
+    pub fn sample_direct(&self, hit: &Hit, brdf: &dyn BRDF, wo: &Vector3<f64>, samples: &MasterSampleSets,
+                         set_index: usize, sample_index: usize) -> Color {
+        if self.lights.is_empty() || hit.depth >= self.job_config.max_trace_depth {
+            return Color::black();
+        }
+
+        // Use the first dimension of the sample to choose a light, then
+        // rescale it to [0, 1) to choose a point on that light.
+        let sample = &samples.light_sets[set_index][hit.depth - 1][sample_index];
+        let num_lights = self.lights.len();
+        let scaled = sample.x * num_lights as f64;
+        let light_index = std::cmp::min(scaled as usize, num_lights - 1);
+        let light = &self.lights[light_index];
+        let light_sample = UnitSquareSample {
+            x: scaled - light_index as f64,
+            y: sample.y,
+        };
+
+        let p = light.shape.sample(&light_sample);
+        let d = p - hit.local_hit_point;
+        let dist = d.norm();
+        let wi = d / dist;
+
+        let ndotwi = hit.normal.dot(&wi);
+        let light_pdf = light.pdf(&hit.local_hit_point, &p) / num_lights as f64;
+        if ndotwi <= 0.0 || light_pdf <= 0.0 {
+            return Color::black();
+        }
+
+        let shadow_ray = Ray {
+            origin: hit.local_hit_point,
+            direction: wi,
+        };
+
+        match self.hit(&shadow_ray, hit.depth + 1) {
+            Some(h) if h.distance < dist * (1.0 - SHADOW_EPSILON) => Color::black(),
+            _ => {
+                let weight = power_heuristic(light_pdf, brdf.pdf(hit, wo, &wi));
+                brdf.f(hit, wo, &wi) * light.radiance_at(&p) * (ndotwi * weight / light_pdf)
+            },
+        }
+    }
+
     pub fn shade(&self, r: &Ray, depth: usize, samples: &MasterSampleSets,
                  set_index: usize, sample_index: usize) -> Color {
         if depth > self.job_config.max_trace_depth {
@@ -1,10 +1,23 @@
 
-use nalgebra::{Vector3, Point3};
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+use nalgebra::{Vector3, Point2, Point3};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, MapAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
 
 use crate::constants::*;
 use crate::common::*;
 use crate::materials::*;
+use crate::brdf::ComplexIor;
 use crate::color::Color;
+use crate::texture::TextureData;
+use crate::mesh::MeshGeometry;
+use crate::scene::ShapeData;
+use crate::samplers::UnitSquareSample;
 
 pub struct Sphere {
     pub data: SphereData,
@@ -13,30 +26,95 @@ pub struct Sphere {
 }
 
 #[derive(Clone)]
-#[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SphereData {
     pub center: Point3<f64>,
     pub radius: f64,
-    pub material: MaterialData,
+    pub material: MaterialRef,
     pub invert: bool,
 }
 
+// An infinite plane. Its UV coordinates are distances in scene units
+// along two directions in the plane from `point`.
 pub struct Plane {
     pub data: PlaneData,
     pub material: Box<dyn Material>,
+    pub u_axis: Vector3<f64>,
+    pub v_axis: Vector3<f64>,
 }
 
 #[derive(Clone)]
-#[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PlaneData {
     pub point: Point3<f64>,
     pub normal: Vector3<f64>,
-    pub material: MaterialData,
+    pub material: MaterialRef,
+}
+
+// A parallelogram with one corner at `corner` and edges `a` and `b`. Its
+// normal is a x b; if it is one-sided, only rays approaching from the
+// side the normal faces will hit it. Its UV coordinates go from 0 to 1
+// along a and b.
+pub struct Rectangle {
+    pub data: RectangleData,
+    pub material: Box<dyn Material>,
+    pub normal: Vector3<f64>,
+    pub area: f64,
+    pub bbox: BoundingBox,
+}
+
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RectangleData {
+    pub corner: Point3<f64>,
+    pub a: Vector3<f64>,
+    pub b: Vector3<f64>,
+    pub material: MaterialRef,
+    #[serde(default)]
+    pub one_sided: bool,
+}
+
+// A triangle mesh read from a Wavefront OBJ file. The file is named in
+// the scene, and its geometry is loaded by SceneData::load_meshes before
+// the scene is sent to workers.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MeshData {
+    pub file: String,
+    pub material: MaterialRef,
+    #[serde(default)]
+    pub geometry: Option<MeshGeometry>,
+}
+
+// A copy of another shape, given in full or by the name of one in the
+// scene's prototypes table, placed in the scene with a transform. The
+// shape is scaled, then rotated about the x, y and z axes in that order,
+// and then translated.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstanceData {
+    pub shape: ShapeRef,
+    #[serde(default = "default_translate")]
+    pub translate: Vector3<f64>,
+    // Angles in degrees.
+    #[serde(default = "default_rotate")]
+    pub rotate: Vector3<f64>,
+    #[serde(default = "default_scale")]
+    pub scale: Vector3<f64>,
+}
+
+fn default_translate() -> Vector3<f64> {
+    Vector3::zeros()
+}
+
+fn default_rotate() -> Vector3<f64> {
+    Vector3::zeros()
+}
+
+fn default_scale() -> Vector3<f64> {
+    Vector3::new(1.0, 1.0, 1.0)
 }
 
-#[derive(Copy)]
 #[derive(Clone)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MaterialData {
@@ -44,42 +122,226 @@ pub enum MaterialData {
     Emissive(EmissiveData),
     Reflective(ReflectiveData),
     GlossyReflective(GlossyReflectiveData),
+    Dielectric(DielectricData),
+    Principled(PrincipledData),
+}
+
+// Something given in full or by the name of an entry in one of the
+// scene's tables, such as a shape's material. In scene files, a name is
+// just a string.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Ref<T> {
+    Named(String),
+    Inline(T),
+}
+
+// A shape's material, which may name one in the scene's materials table.
+pub type MaterialRef = Ref<MaterialData>;
+
+// An instance's shape, which may name one in the scene's prototypes
+// table.
+pub type ShapeRef = Ref<Box<ShapeData>>;
+
+// How a Ref is stored in formats that are not meant for people, such as
+// the network protocol.
+#[derive(Serialize, Deserialize)]
+enum RefData<T> {
+    Named(String),
+    Inline(T),
+}
+
+impl<T> Ref<T> {
+    // Look up a named entry in the table it refers to. The table's values
+    // may be a borrowed form of T, e.g. ShapeData for Box<ShapeData>.
+    pub fn resolve<'a, U>(&'a self, table: &'a BTreeMap<String, U>) -> Option<&'a U>
+        where T: Borrow<U>
+    {
+        match self {
+            Ref::Named(name) => table.get(name),
+            Ref::Inline(v) => Some(v.borrow()),
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Ref<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            match self {
+                Ref::Named(name) => serializer.serialize_str(name),
+                Ref::Inline(v) => v.serialize(serializer),
+            }
+        } else {
+            match self {
+                Ref::Named(name) => RefData::Named(name.clone()),
+                Ref::Inline(v) => RefData::Inline(v),
+            }.serialize(serializer)
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Ref<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(RefVisitor(PhantomData))
+        } else {
+            Ok(match RefData::deserialize(deserializer)? {
+                RefData::Named(name) => Ref::Named(name),
+                RefData::Inline(v) => Ref::Inline(v),
+            })
+        }
+    }
+}
+
+struct RefVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for RefVisitor<T> {
+    type Value = Ref<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a definition or the name of one")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Ref<T>, E> {
+        Ok(Ref::Named(name.to_string()))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Ref<T>, A::Error> {
+        T::deserialize(MapAccessDeserializer::new(map)).map(Ref::Inline)
+    }
+}
+
+impl MaterialData {
+    // The name of this kind of material, as used in scene files.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MaterialData::Matte(_) => "Matte",
+            MaterialData::Emissive(_) => "Emissive",
+            MaterialData::Reflective(_) => "Reflective",
+            MaterialData::GlossyReflective(_) => "GlossyReflective",
+            MaterialData::Dielectric(_) => "Dielectric",
+            MaterialData::Principled(_) => "Principled",
+        }
+    }
+
+    // The textures this material gets its colors from.
+    pub fn textures(&self) -> Vec<&TextureData> {
+        match self {
+            MaterialData::Matte(m) => vec![&m.diffuse_color, &m.ambient_color],
+            MaterialData::Emissive(e) => vec![&e.color],
+            MaterialData::Reflective(r) => vec![&r.reflect_color],
+            MaterialData::GlossyReflective(g) => vec![&g.reflect_color],
+            MaterialData::Dielectric(d) => d.absorption_color.iter().collect(),
+            MaterialData::Principled(p) => vec![&p.base_color],
+        }
+    }
+
+    pub fn textures_mut(&mut self) -> Vec<&mut TextureData> {
+        match self {
+            MaterialData::Matte(m) => vec![&mut m.diffuse_color, &mut m.ambient_color],
+            MaterialData::Emissive(e) => vec![&mut e.color],
+            MaterialData::Reflective(r) => vec![&mut r.reflect_color],
+            MaterialData::GlossyReflective(g) => vec![&mut g.reflect_color],
+            MaterialData::Dielectric(d) => d.absorption_color.iter_mut().collect(),
+            MaterialData::Principled(p) => vec![&mut p.base_color],
+        }
+    }
 }
 
 #[derive(Clone)]
-#[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MatteData {
-    pub diffuse_color: Color,
-    pub ambient_color: Color,
+    pub diffuse_color: TextureData,
+    pub ambient_color: TextureData,
     pub diffuse_coefficient: f64,
 }
 
 #[derive(Clone)]
-#[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmissiveData {
-    pub color: Color,
+    pub color: TextureData,
     pub power: f64,
 }
 
 #[derive(Clone)]
-#[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ReflectiveData {
     pub reflect_amount: f64,
-    pub reflect_color: Color,
+    pub reflect_color: TextureData,
 }
 
+// Rendered as a metal of the given color with the Principled material's
+// BRDF, at the roughness whose highlights most resemble a Phong lobe of
+// the given exponent.
 #[derive(Clone)]
-#[derive(Copy)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GlossyReflectiveData {
     pub reflect_amount: f64,
-    pub reflect_color: Color,
+    pub reflect_color: TextureData,
     pub reflect_exponent: f64,
 }
 
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DielectricData {
+    pub ior: f64,
+    #[serde(default)]
+    pub absorption_color: Option<TextureData>,
+}
+
+// A metallic/roughness material like those of most PBR tools. The
+// metallic fraction and roughness range from 0 to 1. A metal reflects
+// with its base color, unless a conductor is given, in which case it
+// reflects like that conductor, tinted by the base color.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrincipledData {
+    pub base_color: TextureData,
+    pub metallic: f64,
+    pub roughness: f64,
+    // The index of refraction of the specular coat of a non-metal.
+    #[serde(default = "default_coat_ior")]
+    pub ior: f64,
+    #[serde(default)]
+    pub conductor: Option<ConductorData>,
+}
+
+fn default_coat_ior() -> f64 {
+    1.5
+}
+
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ConductorData {
+    Gold,
+    Silver,
+    Copper,
+    Aluminium,
+    Custom(ComplexIorData),
+}
+
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ComplexIorData {
+    pub eta: Color,
+    pub k: Color,
+}
+
+impl ConductorData {
+    // The conductor's complex index of refraction at the wavelengths of
+    // red, green and blue light.
+    pub fn ior(&self) -> ComplexIor {
+        let (eta, k) = match self {
+            ConductorData::Gold => (Color::new(0.143, 0.374, 1.442), Color::new(3.983, 2.385, 1.603)),
+            ConductorData::Silver => (Color::new(0.155, 0.117, 0.138), Color::new(4.828, 3.122, 2.147)),
+            ConductorData::Copper => (Color::new(0.200, 0.924, 1.102), Color::new(3.912, 2.452, 2.142)),
+            ConductorData::Aluminium => (Color::new(1.657, 0.880, 0.521), Color::new(9.224, 6.270, 4.837)),
+            ConductorData::Custom(c) => (c.eta, c.k),
+        };
+        ComplexIor { eta, k }
+    }
+}
+
 #[derive(Clone)]
 #[derive(Copy)]
 pub struct BoundingBox {
@@ -96,7 +358,50 @@ fn max(a: f64, b: f64) -> f64 {
 }
 
 impl BoundingBox {
-    fn hit<'a>(&'a self, r: &Ray) -> bool {
+    pub fn from_points<'a, I: Iterator<Item=&'a Point3<f64>>>(points: I) -> Self {
+        let mut corner0 = Point3::new(std::f64::INFINITY, std::f64::INFINITY, std::f64::INFINITY);
+        let mut corner1 = Point3::new(std::f64::NEG_INFINITY, std::f64::NEG_INFINITY, std::f64::NEG_INFINITY);
+
+        for p in points {
+            corner0 = Point3::new(min(corner0.x, p.x), min(corner0.y, p.y), min(corner0.z, p.z));
+            corner1 = Point3::new(max(corner1.x, p.x), max(corner1.y, p.y), max(corner1.z, p.z));
+        }
+
+        Self {
+            corner0, corner1,
+        }
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> Self {
+        Self {
+            corner0: Point3::new(min(self.corner0.x, other.corner0.x),
+                                 min(self.corner0.y, other.corner0.y),
+                                 min(self.corner0.z, other.corner0.z)),
+            corner1: Point3::new(max(self.corner1.x, other.corner1.x),
+                                 max(self.corner1.y, other.corner1.y),
+                                 max(self.corner1.z, other.corner1.z)),
+        }
+    }
+
+    pub fn centroid(&self) -> Point3<f64> {
+        Point3::from((self.corner0.coords + self.corner1.coords) * 0.5)
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.corner1 - self.corner0;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn hit<'a>(&'a self, r: &Ray) -> bool {
+        self.hit_distance(r).is_some()
+    }
+
+    // Returns the distance along the ray at which it enters this box, if
+    // it hits the box at all. The distance is negative if the ray starts
+    // inside the box. Boxes may be flat (e.g. around an axis-aligned
+    // triangle), so a ray that enters and leaves at the same distance
+    // still counts as a hit.
+    pub fn hit_distance(&self, r: &Ray) -> Option<f64> {
         let ox = r.origin.x;
         let oy = r.origin.y;
         let oz = r.origin.z;
@@ -128,21 +433,48 @@ impl BoundingBox {
         let t0 = max(tx_min, max(ty_min, tz_min));
         let t1 = min(tx_max, min(ty_max, tz_max));
 
-        (t0 < t1 && t1 > T_MIN)
+        if t0 <= t1 && t1 > T_MIN {
+            Some(t0)
+        } else {
+            None
+        }
+    }
+}
+
+impl Plane {
+    pub fn new(data: PlaneData, material: Box<dyn Material>) -> Self {
+        // Texture axes follow the world axis least aligned with the
+        // normal, so a floor with a y normal maps u to x and v to z
+        let n = data.normal.normalize();
+        let axes = [Vector3::x(), Vector3::y(), Vector3::z()];
+        let least_aligned = axes.iter()
+            .min_by(|a, b| a.dot(&n).abs().partial_cmp(&b.dot(&n).abs()).unwrap())
+            .unwrap();
+        let u_axis = (least_aligned - n * least_aligned.dot(&n)).normalize();
+        let v_axis = u_axis.cross(&n);
+
+        Self { data, material, u_axis, v_axis }
     }
 }
 
 impl Intersectable for Plane {
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        None
+    }
+
     fn hit<'a>(&'a self, r: &Ray, depth: usize) -> Option<Hit<'a>> {
         let t = (self.data.point - r.origin).dot(&self.data.normal) / (r.direction.dot(&self.data.normal));
 
         if t > T_MIN {
+            let p = r.origin + t * r.direction;
+            let d = p - self.data.point;
             Some(Hit {
                 ray: r.clone(),
                 depth,
                 distance: t,
                 normal: self.data.normal,
-                local_hit_point: r.origin + t * r.direction,
+                uv: Point2::new(d.dot(&self.u_axis), d.dot(&self.v_axis)),
+                local_hit_point: p,
                 material: self.material.as_ref(),
             })
         } else {
@@ -151,6 +483,88 @@ impl Intersectable for Plane {
     }
 }
 
+impl Rectangle {
+    pub fn new(data: RectangleData, material: Box<dyn Material>) -> Self {
+        let n = data.a.cross(&data.b);
+        let corners = [
+            data.corner,
+            data.corner + data.a,
+            data.corner + data.b,
+            data.corner + data.a + data.b,
+        ];
+
+        Self {
+            normal: n.normalize(),
+            area: n.norm(),
+            bbox: BoundingBox::from_points(corners.iter()),
+            data,
+            material,
+        }
+    }
+
+    // The coordinates of a point in the rectangle's plane along its
+    // edges, so that the point is corner + a * x + b * y. The edges need
+    // not be perpendicular.
+    fn coordinates(&self, p: &Point3<f64>) -> (f64, f64) {
+        let d = p - self.data.corner;
+        (d.cross(&self.data.b).dot(&self.normal) / self.area,
+         self.data.a.cross(&d).dot(&self.normal) / self.area)
+    }
+}
+
+impl Intersectable for Rectangle {
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(self.bbox)
+    }
+
+    fn hit<'a>(&'a self, r: &Ray, depth: usize) -> Option<Hit<'a>> {
+        let ddotn = r.direction.dot(&self.normal);
+        if ddotn == 0.0 || (self.data.one_sided && ddotn > 0.0) {
+            return None;
+        }
+
+        let t = (self.data.corner - r.origin).dot(&self.normal) / ddotn;
+        if t <= T_MIN {
+            return None;
+        }
+
+        let p = r.origin + t * r.direction;
+        let (x, y) = self.coordinates(&p);
+        if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+            return None;
+        }
+
+        Some(Hit {
+            ray: r.clone(),
+            depth,
+            distance: t,
+            normal: self.normal,
+            uv: Point2::new(x, y),
+            local_hit_point: p,
+            material: self.material.as_ref(),
+        })
+    }
+}
+
+impl Sampleable for Rectangle {
+    fn sample(&self, s: &UnitSquareSample) -> Point3<f64> {
+        self.data.corner + self.data.a * s.x + self.data.b * s.y
+    }
+
+    fn normal_at(&self, _p: &Point3<f64>) -> Vector3<f64> {
+        self.normal
+    }
+
+    fn uv_at(&self, p: &Point3<f64>) -> Point2<f64> {
+        let (x, y) = self.coordinates(p);
+        Point2::new(x, y)
+    }
+
+    fn pdf(&self) -> f64 {
+        1.0 / self.area
+    }
+}
+
 impl Sphere {
     pub fn new(data: SphereData, material: Box<dyn Material>) -> Self {
         let delta = Vector3::new(data.radius, data.radius, data.radius);
@@ -168,7 +582,38 @@ impl Sphere {
     }
 }
 
+impl Sampleable for Sphere {
+    fn sample(&self, s: &UnitSquareSample) -> Point3<f64> {
+        let z = 1.0 - 2.0 * s.y;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * s.x;
+        self.data.center + Vector3::new(r * phi.cos(), r * phi.sin(), z) * self.data.radius
+    }
+
+    fn normal_at(&self, p: &Point3<f64>) -> Vector3<f64> {
+        let invert_val = if self.data.invert { -1.0 } else { 1.0 };
+        (p - self.data.center) * invert_val / self.data.radius
+    }
+
+    // Longitude and latitude, with v going from 0 at the bottom (-y) to
+    // 1 at the top.
+    fn uv_at(&self, p: &Point3<f64>) -> Point2<f64> {
+        let d = (p - self.data.center) / self.data.radius;
+        let u = 0.5 + d.z.atan2(d.x) * 0.5 * INV_PI;
+        let v = (-d.y).clamp(-1.0, 1.0).acos() * INV_PI;
+        Point2::new(u, v)
+    }
+
+    fn pdf(&self) -> f64 {
+        INV_PI * 0.25 / (self.data.radius * self.data.radius)
+    }
+}
+
 impl Intersectable for Sphere {
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(self.bbox)
+    }
+
     fn hit<'a>(&'a self, r: &Ray, depth: usize) -> Option<Hit<'a>> {
         if !self.bbox.hit(&r) {
             None
@@ -188,23 +633,27 @@ impl Intersectable for Sphere {
                 let t = (-b - e) / denom;
 
                 if t > T_MIN {
+                    let p = r.origin + t * r.direction;
                     Some(Hit {
                         ray: r.clone(),
                         distance: t,
                         depth,
                         normal: (temp + t * r.direction) * invert_val / self.data.radius,
-                        local_hit_point: r.origin + t * r.direction,
+                        uv: self.uv_at(&p),
+                        local_hit_point: p,
                         material: self.material.as_ref(),
                     })
                 } else {
                     let t2 = (-b + e) / denom;
                     if t2 > T_MIN {
+                        let p = r.origin + t2 * r.direction;
                         Some(Hit {
                             ray: r.clone(),
                             distance: t2,
                             depth,
                             normal: (temp + t2 * r.direction) * invert_val / self.data.radius,
-                            local_hit_point: r.origin + t2 * r.direction,
+                            uv: self.uv_at(&p),
+                            local_hit_point: p,
                             material: self.material.as_ref(),
                         })
                     } else {
@@ -0,0 +1,505 @@
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use nalgebra::{Point2, Point3};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+
+use crate::color::Color;
+use crate::common::Hit;
+
+// Where a material gets a color from at each point of a surface. In
+// scene files, a constant color is just [r, g, b], and other textures
+// are given by kind like materials are, e.g. "Checker: { ... }".
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum TextureData {
+    Constant(Color),
+    Image(ImageTextureData),
+    Checker(CheckerData),
+    Gradient(GradientData),
+    Noise(NoiseData),
+}
+
+// How a TextureData is read from formats that are not meant for people,
+// and from maps in those that are. It must have the same variants in
+// the same order as TextureData.
+#[derive(Deserialize)]
+enum TextureDataRepr {
+    Constant(Color),
+    Image(ImageTextureData),
+    Checker(CheckerData),
+    Gradient(GradientData),
+    Noise(NoiseData),
+}
+
+// An image file mapped onto the surface by its UV coordinates, repeating
+// outside of [0, 1]. The image is read by SceneData::load_textures so
+// that it travels with the scene to network nodes.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageTextureData {
+    pub file: String,
+    #[serde(default)]
+    pub image: Option<Arc<TextureImage>>,
+}
+
+// Squares alternating between two textures in UV space.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckerData {
+    pub even: Box<TextureData>,
+    pub odd: Box<TextureData>,
+    // The size of each square in UV units.
+    pub size: f64,
+}
+
+// A linear blend from one texture to another as u or v goes from 0 to 1.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GradientData {
+    pub start: Box<TextureData>,
+    pub end: Box<TextureData>,
+    #[serde(default = "default_axis")]
+    pub axis: GradientAxis,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GradientAxis {
+    U,
+    V,
+}
+
+fn default_axis() -> GradientAxis {
+    GradientAxis::V
+}
+
+// A blend between two textures by Perlin noise in scene space, so that
+// it does not depend on how the surface is parameterized.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NoiseData {
+    pub low: Box<TextureData>,
+    pub high: Box<TextureData>,
+    // The size of the noise's features in scene units.
+    pub scale: f64,
+    // The number of octaves summed; more add finer detail.
+    #[serde(default = "default_octaves")]
+    pub octaves: usize,
+}
+
+fn default_octaves() -> usize {
+    1
+}
+
+// An 8-bit sRGB image, stored top row first.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct TextureImage {
+    pub width: usize,
+    pub height: usize,
+    #[serde(with = "serde_bytes")]
+    pub pixels: Vec<u8>,
+}
+
+impl fmt::Debug for TextureImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TextureImage {{ width: {}, height: {} }}", self.width, self.height)
+    }
+}
+
+impl Serialize for TextureData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TextureData::Constant(c) if serializer.is_human_readable() => (c.r, c.g, c.b).serialize(serializer),
+            TextureData::Constant(c) => serializer.serialize_newtype_variant("TextureData", 0, "Constant", c),
+            TextureData::Image(i) => serializer.serialize_newtype_variant("TextureData", 1, "Image", i),
+            TextureData::Checker(c) => serializer.serialize_newtype_variant("TextureData", 2, "Checker", c),
+            TextureData::Gradient(g) => serializer.serialize_newtype_variant("TextureData", 3, "Gradient", g),
+            TextureData::Noise(n) => serializer.serialize_newtype_variant("TextureData", 4, "Noise", n),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TextureData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(TextureDataVisitor)
+        } else {
+            TextureDataRepr::deserialize(deserializer).map(TextureData::from)
+        }
+    }
+}
+
+impl From<TextureDataRepr> for TextureData {
+    fn from(r: TextureDataRepr) -> Self {
+        match r {
+            TextureDataRepr::Constant(c) => TextureData::Constant(c),
+            TextureDataRepr::Image(i) => TextureData::Image(i),
+            TextureDataRepr::Checker(c) => TextureData::Checker(c),
+            TextureDataRepr::Gradient(g) => TextureData::Gradient(g),
+            TextureDataRepr::Noise(n) => TextureData::Noise(n),
+        }
+    }
+}
+
+struct TextureDataVisitor;
+
+impl<'de> Visitor<'de> for TextureDataVisitor {
+    type Value = TextureData;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a color or a texture")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<TextureData, A::Error> {
+        Color::deserialize(SeqAccessDeserializer::new(seq)).map(TextureData::Constant)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<TextureData, A::Error> {
+        TextureDataRepr::deserialize(MapAccessDeserializer::new(map)).map(TextureData::from)
+    }
+}
+
+impl TextureData {
+    // The name of this kind of texture, as used in scene files.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TextureData::Constant(_) => "Constant",
+            TextureData::Image(_) => "Image",
+            TextureData::Checker(_) => "Checker",
+            TextureData::Gradient(_) => "Gradient",
+            TextureData::Noise(_) => "Noise",
+        }
+    }
+
+    // The textures this one is made from.
+    pub fn parts(&self) -> Vec<&TextureData> {
+        match self {
+            TextureData::Constant(_) | TextureData::Image(_) => vec![],
+            TextureData::Checker(c) => vec![&c.even, &c.odd],
+            TextureData::Gradient(g) => vec![&g.start, &g.end],
+            TextureData::Noise(n) => vec![&n.low, &n.high],
+        }
+    }
+
+    // Read the image files of this texture and the textures it is made
+    // from, resolving relative paths against base_dir.
+    pub fn load_images(&mut self, base_dir: &Path) -> io::Result<()> {
+        let parts = match self {
+            TextureData::Constant(_) => vec![],
+            TextureData::Image(i) => {
+                if i.image.is_none() {
+                    i.image = Some(Arc::new(TextureImage::load(&base_dir.join(&i.file))?));
+                }
+                vec![]
+            },
+            TextureData::Checker(c) => vec![&mut c.even, &mut c.odd],
+            TextureData::Gradient(g) => vec![&mut g.start, &mut g.end],
+            TextureData::Noise(n) => vec![&mut n.low, &mut n.high],
+        };
+
+        for p in parts {
+            p.load_images(base_dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn image_error(path: &Path, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+}
+
+impl TextureImage {
+    // Read a PNG or JPEG file, chosen by the file's extension.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        let f = BufReader::new(File::open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?);
+
+        match ext.as_deref() {
+            Some("png") => Self::load_png(path, f),
+            Some("jpg") | Some("jpeg") => Self::load_jpeg(path, f),
+            _ => Err(image_error(path, "unsupported image format, expected PNG or JPEG".to_string())),
+        }
+    }
+
+    fn load_png(path: &Path, f: BufReader<File>) -> io::Result<Self> {
+        // The decoder expands palettes and strips 16-bit samples to 8
+        // bits by default.
+        let (info, mut reader) = png::Decoder::new(f).read_info()
+            .map_err(|e| image_error(path, format!("{}", e)))?;
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf).map_err(|e| image_error(path, format!("{}", e)))?;
+
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => return Err(image_error(path, "unexpected palette image".to_string())),
+        };
+
+        Ok(Self::from_samples(info.width as usize, info.height as usize, channels, &buf))
+    }
+
+    fn load_jpeg(path: &Path, f: BufReader<File>) -> io::Result<Self> {
+        let mut decoder = jpeg_decoder::Decoder::new(f);
+        let buf = decoder.decode().map_err(|e| image_error(path, format!("{}", e)))?;
+        let info = match decoder.info() {
+            Some(i) => i,
+            None => return Err(image_error(path, "no image information".to_string())),
+        };
+
+        let channels = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => 1,
+            jpeg_decoder::PixelFormat::RGB24 => 3,
+            f => return Err(image_error(path, format!("unsupported pixel format {:?}", f))),
+        };
+
+        Ok(Self::from_samples(info.width as usize, info.height as usize, channels, &buf))
+    }
+
+    // Build an RGB image from 8-bit samples with the given number of
+    // channels per pixel: grey or RGB, optionally followed by alpha,
+    // which is dropped.
+    fn from_samples(width: usize, height: usize, channels: usize, samples: &[u8]) -> Self {
+        let pixels = samples.chunks(channels).take(width * height).flat_map(|p| {
+            if channels < 3 { [p[0], p[0], p[0]] } else { [p[0], p[1], p[2]] }
+        }).collect();
+
+        Self { width, height, pixels }
+    }
+}
+
+pub trait Texture: Sync + Send {
+    // The color at a point on a surface, given the point's UV
+    // coordinates and its position in the scene.
+    fn color_at(&self, uv: &Point2<f64>, p: &Point3<f64>) -> Color;
+
+    fn at_hit(&self, hit: &Hit) -> Color {
+        self.color_at(&hit.uv, &hit.local_hit_point)
+    }
+}
+
+impl Texture for Color {
+    fn color_at(&self, _uv: &Point2<f64>, _p: &Point3<f64>) -> Color {
+        *self
+    }
+}
+
+pub fn texture_from_data(d: &TextureData) -> Box<dyn Texture> {
+    match d {
+        TextureData::Constant(c) => Box::new(*c),
+        TextureData::Image(i) => {
+            let image = match &i.image {
+                Some(image) => image.clone(),
+                None => panic!("Texture image '{}' was not loaded", i.file),
+            };
+            Box::new(ImageTexture::new(image))
+        },
+        TextureData::Checker(c) => {
+            Box::new(Checker {
+                even: texture_from_data(&c.even),
+                odd: texture_from_data(&c.odd),
+                size: c.size,
+            })
+        },
+        TextureData::Gradient(g) => {
+            Box::new(Gradient {
+                start: texture_from_data(&g.start),
+                end: texture_from_data(&g.end),
+                axis: g.axis,
+            })
+        },
+        TextureData::Noise(n) => {
+            Box::new(Noise {
+                low: texture_from_data(&n.low),
+                high: texture_from_data(&n.high),
+                scale: n.scale,
+                octaves: n.octaves,
+                perlin: Perlin::new(),
+            })
+        },
+    }
+}
+
+fn srgb_decode(v: f64) -> f64 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Looks up colors in an image with bilinear filtering. The image's
+// bottom row is at v = 0.
+pub struct ImageTexture {
+    image: Arc<TextureImage>,
+    // The linear value of each 8-bit sRGB sample.
+    linear: Vec<f64>,
+}
+
+impl ImageTexture {
+    pub fn new(image: Arc<TextureImage>) -> Self {
+        Self {
+            image,
+            linear: (0..256).map(|v| srgb_decode(v as f64 / 255.0)).collect(),
+        }
+    }
+
+    fn pixel(&self, col: usize, row: usize) -> Color {
+        let i = (row * self.image.width + col) * 3;
+        let p = &self.image.pixels[i..i + 3];
+        Color::new(self.linear[p[0] as usize], self.linear[p[1] as usize], self.linear[p[2] as usize])
+    }
+}
+
+impl Texture for ImageTexture {
+    fn color_at(&self, uv: &Point2<f64>, _p: &Point3<f64>) -> Color {
+        let (w, h) = (self.image.width, self.image.height);
+        let x = (uv.x - uv.x.floor()) * w as f64 - 0.5;
+        let y = (1.0 - (uv.y - uv.y.floor())) * h as f64 - 0.5;
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+
+        // Neighbouring pixels wrap around the edges of the image
+        let col0 = (x.floor() as isize).rem_euclid(w as isize) as usize;
+        let row0 = (y.floor() as isize).rem_euclid(h as isize) as usize;
+        let col1 = (col0 + 1) % w;
+        let row1 = (row0 + 1) % h;
+
+        let top = self.pixel(col0, row0) * (1.0 - fx) + self.pixel(col1, row0) * fx;
+        let bottom = self.pixel(col0, row1) * (1.0 - fx) + self.pixel(col1, row1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+pub struct Checker {
+    even: Box<dyn Texture>,
+    odd: Box<dyn Texture>,
+    size: f64,
+}
+
+impl Texture for Checker {
+    fn color_at(&self, uv: &Point2<f64>, p: &Point3<f64>) -> Color {
+        let square = (uv.x / self.size).floor() + (uv.y / self.size).floor();
+        if square.rem_euclid(2.0) == 0.0 {
+            self.even.color_at(uv, p)
+        } else {
+            self.odd.color_at(uv, p)
+        }
+    }
+}
+
+pub struct Gradient {
+    start: Box<dyn Texture>,
+    end: Box<dyn Texture>,
+    axis: GradientAxis,
+}
+
+impl Texture for Gradient {
+    fn color_at(&self, uv: &Point2<f64>, p: &Point3<f64>) -> Color {
+        let t = match self.axis {
+            GradientAxis::U => uv.x,
+            GradientAxis::V => uv.y,
+        }.clamp(0.0, 1.0);
+
+        self.start.color_at(uv, p) * (1.0 - t) + self.end.color_at(uv, p) * t
+    }
+}
+
+pub struct Noise {
+    low: Box<dyn Texture>,
+    high: Box<dyn Texture>,
+    scale: f64,
+    octaves: usize,
+    perlin: Perlin,
+}
+
+impl Texture for Noise {
+    fn color_at(&self, uv: &Point2<f64>, p: &Point3<f64>) -> Color {
+        let mut sum = 0.0;
+        let mut total_weight = 0.0;
+        let mut weight = 1.0;
+        let mut q = p.coords / self.scale;
+
+        for _ in 0..self.octaves {
+            sum += self.perlin.noise(q.x, q.y, q.z) * weight;
+            total_weight += weight;
+            weight *= 0.5;
+            q *= 2.0;
+        }
+
+        let t = (0.5 + 0.5 * sum / total_weight).clamp(0.0, 1.0);
+        self.low.color_at(uv, p) * (1.0 - t) + self.high.color_at(uv, p) * t
+    }
+}
+
+// Ken Perlin's improved gradient noise. The permutation is the same
+// everywhere so that every node renders the same noise.
+struct Perlin {
+    perm: Vec<usize>,
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+fn grad(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+impl Perlin {
+    fn new() -> Self {
+        // Shuffle with a fixed xorshift sequence rather than rand, whose
+        // output is not the same from run to run.
+        let mut perm: Vec<usize> = (0..256).collect();
+        let mut state: u32 = 0x9e37_79b9;
+        for i in (1..256).rev() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            perm.swap(i, state as usize % (i + 1));
+        }
+
+        let doubled = perm.iter().chain(perm.iter()).cloned().collect();
+        Self { perm: doubled }
+    }
+
+    // Noise in roughly [-1, 1].
+    fn noise(&self, x: f64, y: f64, z: f64) -> f64 {
+        let (xf, yf, zf) = (x.floor(), y.floor(), z.floor());
+        let (xi, yi, zi) = ((xf as i64 & 255) as usize, (yf as i64 & 255) as usize, (zf as i64 & 255) as usize);
+        let (x, y, z) = (x - xf, y - yf, z - zf);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let p = &self.perm;
+
+        let a = p[xi] + yi;
+        let aa = p[a] + zi;
+        let ab = p[a + 1] + zi;
+        let b = p[xi + 1] + yi;
+        let ba = p[b] + zi;
+        let bb = p[b + 1] + zi;
+
+        lerp(w, lerp(v, lerp(u, grad(p[aa], x, y, z), grad(p[ba], x - 1.0, y, z)),
+                        lerp(u, grad(p[ab], x, y - 1.0, z), grad(p[bb], x - 1.0, y - 1.0, z))),
+                lerp(v, lerp(u, grad(p[aa + 1], x, y, z - 1.0), grad(p[ba + 1], x - 1.0, y, z - 1.0)),
+                        lerp(u, grad(p[ab + 1], x, y - 1.0, z - 1.0), grad(p[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+    }
+}
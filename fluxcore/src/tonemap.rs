@@ -0,0 +1,113 @@
+
+use std::str::FromStr;
+
+use crate::color::Color;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+#[derive(PartialEq)]
+pub enum ToneMapper {
+    // Clip each channel to [0, 1].
+    Clamp,
+    // Compress luminance with L / (1 + L), preserving hue.
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic reference curve.
+    Aces,
+}
+
+impl FromStr for ToneMapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "aces" => Ok(ToneMapper::Aces),
+            _ => Err(format!("unknown tone mapper '{}', expected one of clamp, reinhard, aces", s)),
+        }
+    }
+}
+
+// How linear HDR radiance is turned into display values for low dynamic
+// range outputs (PPM, PNG and the preview window). Floating point
+// outputs bypass this entirely.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToneMapping {
+    #[serde(default = "default_operator")]
+    pub operator: ToneMapper,
+    // Exposure adjustment in stops, applied before the operator.
+    #[serde(default)]
+    pub exposure: f64,
+    // Whether to encode the result with the sRGB transfer curve; turn
+    // this off to get linear values.
+    #[serde(default = "default_srgb")]
+    pub srgb: bool,
+}
+
+fn default_operator() -> ToneMapper {
+    ToneMapper::Clamp
+}
+
+fn default_srgb() -> bool {
+    true
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: default_operator(),
+            exposure: 0.0,
+            srgb: default_srgb(),
+        }
+    }
+}
+
+fn clamp01(v: f64) -> f64 {
+    if v.is_nan() || v < 0.0 {
+        0.0
+    } else if v > 1.0 {
+        1.0
+    } else {
+        v
+    }
+}
+
+fn aces(x: f64) -> f64 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn srgb_encode(v: f64) -> f64 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl ToneMapping {
+    // Map a linear radiance value to a display value with each channel
+    // in [0, 1].
+    pub fn apply(&self, c: &Color) -> Color {
+        let exposed = *c * 2f64.powf(self.exposure);
+
+        let mapped = match self.operator {
+            ToneMapper::Clamp => exposed,
+            ToneMapper::Reinhard => {
+                let l = exposed.luminance();
+                if l > 0.0 { exposed * (1.0 / (1.0 + l)) } else { Color::black() }
+            },
+            ToneMapper::Aces => Color::new(aces(exposed.r), aces(exposed.g), aces(exposed.b)),
+        };
+
+        let clamped = Color::new(clamp01(mapped.r), clamp01(mapped.g), clamp01(mapped.b));
+
+        if self.srgb {
+            Color::new(srgb_encode(clamped.r), srgb_encode(clamped.g), srgb_encode(clamped.b))
+        } else {
+            clamped
+        }
+    }
+}
@@ -6,6 +6,7 @@ use samplers::Sampler;
 
 use crate::sampling::MasterSampleSets;
 use crate::color::Color;
+use crate::constants::MIN_ADAPTIVE_LUMINANCE;
 use crate::scene::{Scene, CameraSettings, CameraBasis};
 use crate::common::Ray;
 use crate::manager::WorkUnitResult;
@@ -15,6 +16,7 @@ pub struct Camera {
     pub settings: CameraSettings,
     pub basis: CameraBasis,
     samples: MasterSampleSets,
+    num_sets: usize,
     config: JobConfiguration,
     pub zoom_factor: f64,
     pub view_plane_distance: f64,
@@ -36,11 +38,20 @@ impl Camera {
             view_plane_distance,
             focal_distance,
             lens_radius,
+            num_sets,
             samples: MasterSampleSets::new(&mut s, config.sample_root,
                                            config.max_trace_depth, num_sets),
         }
     }
 
+    // Generate fresh sample sets so that a new pass over the image does
+    // not repeat the sample patterns of the previous one.
+    pub fn resample(&mut self) {
+        let mut s = Sampler::new();
+        self.samples = MasterSampleSets::new(&mut s, self.config.sample_root,
+                                             self.config.max_trace_depth, self.num_sets);
+    }
+
     fn ray_direction(&self, px: f64, py: f64, lx: f64, ly: f64) -> Vector3<f64> {
         let factor = self.focal_distance / self.view_plane_distance;
         let px2 = px * factor;
@@ -50,49 +61,92 @@ impl Camera {
             self.focal_distance * self.basis.w).normalize()
     }
 
+    // Whether a pixel should take another batch of samples, given the
+    // number of samples so far and the running mean and sum of squared
+    // deviations of their luminance.
+    fn needs_samples(&self, count: usize, mean: f64, m2: f64) -> bool {
+        if self.config.noise_threshold <= 0.0 {
+            return false;
+        }
+        // At least two samples are needed to estimate the variance
+        if count < self.config.min_samples.max(2) {
+            return true;
+        }
+        if count >= self.config.max_samples {
+            return false;
+        }
+
+        let variance = m2 / (count - 1) as f64;
+        let std_error = (variance / count as f64).sqrt();
+        std_error / mean.max(MIN_ADAPTIVE_LUMINANCE) > self.config.noise_threshold
+    }
+
     pub fn render(&self, s: &Scene, work: WorkUnit) -> WorkUnitResult {
         let img_h = s.output_settings.image_height;
         let img_w = s.output_settings.image_width;
         let half_img_h = img_h as f64 * 0.5;
         let half_img_w = img_w as f64 * 0.5;
 
-        let pixel_denom = 1.0 / ((self.config.sample_root * self.config.sample_root) as f64);
         let adjusted_pixel_size = s.output_settings.pixel_size / self.zoom_factor;
 
         let rows: Vec<usize> = (work.row_start..=work.row_end).collect();
-        let row_pixel_vecs: Vec<Vec<Color>> = rows.par_iter().map(|row| {
+        let row_results: Vec<(Vec<Color>, Vec<usize>)> = rows.par_iter().map(|row| {
             let sample_set_indexes = self.samples.shuffle_indices();
 
-            let row_pixels = (0..img_w).map(|col| {
+            (work.col_start..=work.col_end).map(|col| {
                 let mut color = Color::black();
-                let pixel_samples = &self.samples.pixel_sets[sample_set_indexes[col] % self.samples.pixel_sets.len()];
-                let disc_samples = &self.samples.disc_sets[sample_set_indexes[col] % self.samples.disc_sets.len()];
-
-                for (index, point) in pixel_samples.iter().enumerate() {
-                    let u = adjusted_pixel_size * (col as f64 - half_img_w + point.x);
-                    let v = adjusted_pixel_size * ((img_h - *row) as f64 - half_img_h + point.y);
-                    let lens_sample = &disc_samples[index];
-                    let lpx = lens_sample.x * self.lens_radius;
-                    let lpy = lens_sample.y * self.lens_radius;
-                    let r = Ray {
-                        direction: self.ray_direction(u, v, lpx, lpy),
-                        origin: self.settings.eye + lpx * self.basis.u + lpy * self.basis.v,
-                    };
-
-                    color += s.shade(&r, 1, &self.samples, sample_set_indexes[col], index);
+                let mut count = 0;
+                let mut mean = 0.0;
+                let mut m2 = 0.0;
+                let mut batch = 0;
+
+                // Each batch uses the next sample set so that repeated
+                // batches for a pixel do not repeat sample patterns.
+                loop {
+                    let set_index = (sample_set_indexes[col] + batch) % self.num_sets;
+                    let pixel_samples = &self.samples.pixel_sets[set_index % self.samples.pixel_sets.len()];
+                    let disc_samples = &self.samples.disc_sets[set_index % self.samples.disc_sets.len()];
+
+                    for (index, point) in pixel_samples.iter().enumerate() {
+                        let u = adjusted_pixel_size * (col as f64 - half_img_w + point.x);
+                        let v = adjusted_pixel_size * ((img_h - *row) as f64 - half_img_h + point.y);
+                        let lens_sample = &disc_samples[index];
+                        let lpx = lens_sample.x * self.lens_radius;
+                        let lpy = lens_sample.y * self.lens_radius;
+                        let r = Ray {
+                            direction: self.ray_direction(u, v, lpx, lpy),
+                            origin: self.settings.eye + lpx * self.basis.u + lpy * self.basis.v,
+                        };
+
+                        let sample = s.shade(&r, 1, &self.samples, set_index, index);
+                        color += sample;
+
+                        // Welford's online variance update
+                        count += 1;
+                        let l = sample.luminance();
+                        let delta = l - mean;
+                        mean += delta / count as f64;
+                        m2 += delta * (l - mean);
+                    }
+
+                    batch += 1;
+                    if !self.needs_samples(count, mean, m2) {
+                        break;
+                    }
                 }
 
-                color *= pixel_denom;
-                color.max_to_one();
-                color
-            }).collect();
-
-            row_pixels
+                color *= 1.0 / count as f64;
+                (color, count)
+            }).unzip()
         }).collect();
 
+        let (rows, sample_counts) = row_results.into_iter().unzip();
+
         WorkUnitResult {
             work_unit: work,
-            rows: row_pixel_vecs,
+            rows,
+            sample_counts,
+            worker: String::new(),
         }
     }
 }
@@ -0,0 +1,365 @@
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use nalgebra::Vector3;
+
+use crate::color::Color;
+use crate::job::JobConfiguration;
+use crate::scene::{SceneData, ShapeData};
+use crate::shapes::{ConductorData, MaterialData, Ref};
+use crate::texture::TextureData;
+
+// Vectors shorter than this cannot be normalized reliably.
+const MIN_VECTOR_LENGTH: f64 = 1e-9;
+
+// A problem with a scene that would make it fail to render or render
+// garbage. The path locates the offending value the way it appears in a
+// scene file, e.g. "shapes[2].Sphere.radius".
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct SceneError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+// Collects the errors found while walking a scene, keeping track of
+// where in the scene we are.
+struct Validator {
+    path: Vec<String>,
+    errors: Vec<SceneError>,
+}
+
+impl Validator {
+    fn within<F>(&mut self, part: String, f: F) where F: FnOnce(&mut Self) {
+        self.path.push(part);
+        f(self);
+        self.path.pop();
+    }
+
+    fn error(&mut self, field: &str, message: String) {
+        let mut path = self.path.join(".");
+        if !field.is_empty() {
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(field);
+        }
+        self.errors.push(SceneError { path, message });
+    }
+
+    fn finite(&mut self, field: &str, v: f64) -> bool {
+        if !v.is_finite() {
+            self.error(field, format!("must be a finite number, not {}", v));
+        }
+        v.is_finite()
+    }
+
+    fn positive(&mut self, field: &str, v: f64) {
+        if self.finite(field, v) && v <= 0.0 {
+            self.error(field, format!("must be greater than zero, not {}", v));
+        }
+    }
+
+    fn non_negative(&mut self, field: &str, v: f64) {
+        if self.finite(field, v) && v < 0.0 {
+            self.error(field, format!("must not be negative, not {}", v));
+        }
+    }
+
+    fn fraction(&mut self, field: &str, v: f64) {
+        if self.finite(field, v) && !(0.0..=1.0).contains(&v) {
+            self.error(field, format!("must be between 0 and 1, not {}", v));
+        }
+    }
+
+    fn vector(&mut self, field: &str, v: &Vector3<f64>) -> bool {
+        let ok = v.iter().all(|c| c.is_finite());
+        if !ok {
+            self.error(field, format!("must have finite components, not [{}, {}, {}]", v.x, v.y, v.z));
+        }
+        ok
+    }
+
+    // A vector that is used as a direction and so must have a length.
+    fn direction(&mut self, field: &str, v: &Vector3<f64>) -> bool {
+        if !self.vector(field, v) {
+            return false;
+        }
+        if v.norm() < MIN_VECTOR_LENGTH {
+            self.error(field, "must not have zero length".to_string());
+            return false;
+        }
+        true
+    }
+
+    fn color(&mut self, field: &str, c: &Color) {
+        let components = [c.r, c.g, c.b];
+        if components.iter().any(|v| !v.is_finite() || *v < 0.0) {
+            self.error(field, format!("must have finite, non-negative components, not [{}, {}, {}]", c.r, c.g, c.b));
+        }
+    }
+
+    // A texture, or just a color.
+    fn texture(&mut self, field: &str, t: &TextureData) {
+        let c = match t {
+            TextureData::Constant(c) => return self.color(field, c),
+            _ => t,
+        };
+
+        self.within(format!("{}.{}", field, c.kind()), |v| {
+            match c {
+                TextureData::Constant(_) => (),
+                TextureData::Image(i) => {
+                    if i.file.trim().is_empty() {
+                        v.error("file", "must name a PNG or JPEG file".to_string());
+                    }
+                },
+                TextureData::Checker(c) => {
+                    v.texture("even", &c.even);
+                    v.texture("odd", &c.odd);
+                    v.positive("size", c.size);
+                },
+                TextureData::Gradient(g) => {
+                    v.texture("start", &g.start);
+                    v.texture("end", &g.end);
+                },
+                TextureData::Noise(n) => {
+                    v.texture("low", &n.low);
+                    v.texture("high", &n.high);
+                    v.positive("scale", n.scale);
+                    if n.octaves == 0 {
+                        v.error("octaves", "must be at least 1".to_string());
+                    }
+                },
+            }
+        });
+    }
+
+    fn scene(&mut self, s: &SceneData) {
+        if s.scene_name.trim().is_empty() {
+            self.error("scene_name", "must not be empty".to_string());
+        }
+
+        self.within("output_settings".to_string(), |v| {
+            let o = &s.output_settings;
+            if o.image_width == 0 {
+                v.error("image_width", "must be greater than zero".to_string());
+            }
+            if o.image_height == 0 {
+                v.error("image_height", "must be greater than zero".to_string());
+            }
+            v.positive("pixel_size", o.pixel_size);
+            v.within("tone_mapping".to_string(), |v| {
+                v.finite("exposure", o.tone_mapping.exposure);
+            });
+        });
+
+        self.color("background", &s.background);
+
+        self.within("camera_settings".to_string(), |v| {
+            let c = &s.camera_settings;
+            v.vector("eye", &c.eye.coords);
+            v.vector("look_at", &c.look_at.coords);
+
+            let view = c.look_at - c.eye;
+            if view.norm() < MIN_VECTOR_LENGTH {
+                v.error("look_at", "must differ from eye".to_string());
+            } else if v.direction("up", &c.up) && c.up.cross(&view.normalize()).norm() < MIN_VECTOR_LENGTH * c.up.norm() {
+                v.error("up", "must not be parallel to the view direction from eye to look_at".to_string());
+            }
+        });
+
+        self.within("camera_data".to_string(), |v| {
+            let c = &s.camera_data;
+            v.positive("zoom_factor", c.zoom_factor);
+            v.positive("view_plane_distance", c.view_plane_distance);
+            v.positive("focal_distance", c.focal_distance);
+            v.non_negative("lens_radius", c.lens_radius);
+        });
+
+        for (name, m) in &s.materials {
+            self.within(format!("materials.{}.{}", name, m.kind()), |v| v.material(m));
+        }
+
+        for (name, p) in &s.prototypes {
+            if contains_prototype(s, p, name, &mut BTreeSet::new()) {
+                self.error(&format!("prototypes.{}", name), "must not contain an instance of itself".to_string());
+            } else {
+                self.within(format!("prototypes.{}.{}", name, p.kind()), |v| v.shape(p, s));
+            }
+        }
+
+        for (i, shape) in s.shapes.iter().enumerate() {
+            self.within(format!("shapes[{}].{}", i, shape.kind()), |v| v.shape(shape, s));
+        }
+    }
+
+    fn job(&mut self, c: &JobConfiguration) {
+        self.non_negative("noise_threshold", c.noise_threshold);
+        if c.noise_threshold > 0.0 {
+            // The variance of a pixel needs at least two samples
+            if c.max_samples < 2 {
+                self.error("max_samples", format!("must be at least 2 with adaptive sampling, not {}", c.max_samples));
+            }
+            if c.min_samples > c.max_samples {
+                self.error("min_samples", format!("must not be greater than max_samples ({}), not {}",
+                                                  c.max_samples, c.min_samples));
+            }
+            // Each pass starts every pixel's statistics afresh, so more
+            // than one pass would take min_samples everywhere every time
+            if c.passes != 1 {
+                self.error("passes", format!("must be 1 with adaptive sampling, not {}", c.passes));
+            }
+        }
+    }
+
+    fn shape(&mut self, shape: &ShapeData, s: &SceneData) {
+        match shape {
+            ShapeData::Sphere(s) => {
+                self.vector("center", &s.center.coords);
+                self.positive("radius", s.radius);
+            },
+            ShapeData::Plane(p) => {
+                self.vector("point", &p.point.coords);
+                self.direction("normal", &p.normal);
+            },
+            ShapeData::Rectangle(r) => {
+                self.vector("corner", &r.corner.coords);
+                if self.direction("a", &r.a) && self.direction("b", &r.b) &&
+                    r.a.cross(&r.b).norm() < MIN_VECTOR_LENGTH * r.a.norm() * r.b.norm() {
+                    self.error("b", "must not be parallel to a".to_string());
+                }
+            },
+            ShapeData::Mesh(m) => {
+                if m.file.trim().is_empty() {
+                    self.error("file", "must name an OBJ file".to_string());
+                }
+            },
+            ShapeData::Instance(i) => {
+                self.vector("translate", &i.translate);
+                self.vector("rotate", &i.rotate);
+                if self.vector("scale", &i.scale) && i.scale.iter().any(|c| *c == 0.0) {
+                    self.error("scale", "must not have a zero component".to_string());
+                }
+
+                match &i.shape {
+                    Ref::Named(name) => {
+                        if !s.prototypes.contains_key(name) {
+                            self.error("shape", format!("there is no prototype named '{}'", name));
+                        }
+                    },
+                    Ref::Inline(shape) => {
+                        self.within(format!("shape.{}", shape.kind()), |v| v.shape(shape, s));
+                    },
+                }
+            },
+        }
+
+        match shape.material() {
+            None => (),
+            Some(Ref::Named(name)) => {
+                if !s.materials.contains_key(name) {
+                    self.error("material", format!("there is no material named '{}'", name));
+                }
+            },
+            Some(Ref::Inline(m)) => {
+                self.within(format!("material.{}", m.kind()), |v| v.material(m));
+            },
+        }
+    }
+
+    fn material(&mut self, material: &MaterialData) {
+        match material {
+            MaterialData::Matte(m) => {
+                self.texture("diffuse_color", &m.diffuse_color);
+                self.texture("ambient_color", &m.ambient_color);
+                self.non_negative("diffuse_coefficient", m.diffuse_coefficient);
+            },
+            MaterialData::Emissive(e) => {
+                self.texture("color", &e.color);
+                self.non_negative("power", e.power);
+            },
+            MaterialData::Reflective(r) => {
+                self.non_negative("reflect_amount", r.reflect_amount);
+                self.texture("reflect_color", &r.reflect_color);
+            },
+            MaterialData::GlossyReflective(g) => {
+                self.non_negative("reflect_amount", g.reflect_amount);
+                self.texture("reflect_color", &g.reflect_color);
+                self.positive("reflect_exponent", g.reflect_exponent);
+            },
+            MaterialData::Dielectric(d) => {
+                self.positive("ior", d.ior);
+                if let Some(a) = &d.absorption_color {
+                    self.texture("absorption_color", a);
+                }
+            },
+            MaterialData::Principled(p) => {
+                self.texture("base_color", &p.base_color);
+                self.fraction("metallic", p.metallic);
+                self.fraction("roughness", p.roughness);
+                self.positive("ior", p.ior);
+                if let Some(ConductorData::Custom(c)) = &p.conductor {
+                    self.within("conductor.Custom".to_string(), |v| {
+                        v.color("eta", &c.eta);
+                        v.color("k", &c.k);
+                    });
+                }
+            },
+        }
+    }
+}
+
+// Whether the shape is or contains an instance of the named prototype,
+// directly or through other prototypes. The prototypes seen so far are
+// skipped, so that cycles not involving the named one end the search.
+fn contains_prototype(s: &SceneData, shape: &ShapeData, name: &str, seen: &mut BTreeSet<String>) -> bool {
+    match shape {
+        ShapeData::Instance(i) => match &i.shape {
+            Ref::Named(n) => {
+                if n == name {
+                    return true;
+                }
+                if !seen.insert(n.clone()) {
+                    return false;
+                }
+                s.prototypes.get(n).map_or(false, |p| contains_prototype(s, p, name, seen))
+            },
+            Ref::Inline(inner) => contains_prototype(s, inner, name, seen),
+        },
+        _ => false,
+    }
+}
+
+impl SceneData {
+    // Check the scene for values that would make it fail to render or
+    // render garbage, returning every problem found.
+    pub fn validate(&self) -> Vec<SceneError> {
+        let mut v = Validator {
+            path: vec![],
+            errors: vec![],
+        };
+        v.scene(self);
+        v.errors
+    }
+}
+
+impl JobConfiguration {
+    // Check the adaptive sampling settings for values that would quietly
+    // be ignored or make it pointless, returning every problem found.
+    pub fn validate(&self) -> Vec<SceneError> {
+        let mut v = Validator {
+            path: vec![],
+            errors: vec![],
+        };
+        v.job(self);
+        v.errors
+    }
+}
@@ -1,11 +1,12 @@
 
-use crossbeam::channel::{Sender, Receiver, unbounded};
+use crossbeam::channel::{Sender, Receiver, RecvTimeoutError, unbounded};
 use std::thread;
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
 use std::io;
+use std::time::{Duration, Instant};
 
 use rayon;
-use serde_cbor::to_writer;
+use serde::de::DeserializeOwned;
 use serde_cbor::StreamDeserializer;
 use serde_cbor::de::IoRead;
 
@@ -14,8 +15,29 @@ use crate::scene::{Scene};
 use crate::trace::Camera;
 use crate::manager::*;
 use crate::job::{Job, WorkUnit};
+use crate::protocol::*;
+use crate::net::{NetStream, ClientSecurity};
+use crate::encoding::{WireEncoding, EncodedEvent, decode_event};
 use crate::debug::d_println;
 
+// A network worker keeps enough work units queued on its node to cover
+// this much of the node's rendering time, so that the node does not sit
+// idle while results and new units are in transit.
+const NETWORK_PREFETCH_SECS: f64 = 0.5;
+const MIN_PIPELINE_DEPTH: usize = 2;
+const MAX_PIPELINE_DEPTH: usize = 32;
+// Until a node's throughput has been measured, queue one extra unit on
+// it for this many of its threads.
+const THREADS_PER_PREFETCH_UNIT: usize = 8;
+// The weight of the newest measurement in a node's throughput estimate.
+const THROUGHPUT_SMOOTHING: f64 = 0.25;
+// How often a network worker waiting on its node checks whether units
+// it sent were completed by other workers in the meantime.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How results rendered by a local worker are marked; network workers
+// mark theirs with their node's endpoint.
+const LOCAL_WORKER_NAME: &str = "local";
+
 pub struct LocalWorker {
     sender: Sender<WorkerRequest>,
     thread_handle: thread::JoinHandle<()>,
@@ -24,6 +46,13 @@ pub struct LocalWorker {
 
 impl LocalWorker {
     pub fn new(num_threads: usize) -> Self {
+        Self::configure_thread_pool(num_threads);
+        Self::spawn(num_threads)
+    }
+
+    // Set the size of the global thread pool that local workers render
+    // on. This only has an effect the first time it is called.
+    pub fn configure_thread_pool(num_threads: usize) {
         let tp_result = rayon::ThreadPoolBuilder::new()
             .num_threads(num_threads)
             .build_global();
@@ -36,15 +65,24 @@ impl LocalWorker {
                          rayon::current_num_threads());
             }
         }
+    }
+
+    // A worker that renders on the global thread pool as it is already
+    // configured. Several such workers can render jobs at the same time,
+    // with the pool dividing its threads between them.
+    pub fn sharing_pool() -> Self {
+        Self::spawn(rayon::current_num_threads())
+    }
 
+    fn spawn(num_threads: usize) -> Self {
         let (s, r): (Sender<WorkerRequest>, Receiver<WorkerRequest>) = unbounded();
 
         let handle = thread::Builder::new().name("LocalWorker".to_string()).spawn(move || {
-            'main: while let Ok(Some((job, recv_unit, send_result, wg))) = r.recv() {
+            'main: while let Ok(Some((job, queue, send_result, wg))) = r.recv() {
                 d_println(format!("Local worker: got job {:?}", job.id));
 
                 let scene = Scene::from_data(job.scene_data, job.config);
-                let camera = Camera::new(scene.camera_settings.clone(),
+                let mut camera = Camera::new(scene.camera_settings.clone(),
                                          scene.camera_basis.clone(),
                                          job.config,
                                          scene.output_settings.image_width,
@@ -53,20 +91,30 @@ impl LocalWorker {
                                          scene.camera_data.focal_distance,
                                          scene.camera_data.lens_radius);
 
-                while let Ok(unit) = recv_unit.recv() {
+                let mut current_pass = 0;
+
+                while let Some(unit) = queue.next() {
                     d_println(format!("Local worker: got work unit {:?}", unit));
 
+                    if unit.pass != current_pass {
+                        camera.resample();
+                        current_pass = unit.pass;
+                    }
+
                     d_println(format!("Starting render"));
-                    let r = camera.render(&scene, unit);
+                    let mut r = camera.render(&scene, unit);
+                    r.worker = LOCAL_WORKER_NAME.to_string();
                     d_println(format!("render done"));
 
+                    if !queue.complete(&unit) {
+                        d_println(format!("Local worker: discarding duplicate result for {:?}", unit));
+                        continue;
+                    }
+
                     let ev = RenderEvent::RowsReady(r);
-                    match send_result.send(Some(ev)) {
-                        Ok(()) => (),
-                        Err(_) => {
-                            d_println(format!("LocalWorker advancing to next job to due result send error"));
-                            continue 'main;
-                        }
+                    if send_result.send(Some(ev)).is_err() {
+                        d_println(format!("LocalWorker advancing to next job to due result send error"));
+                        continue 'main;
                     }
                 }
 
@@ -106,139 +154,358 @@ impl Worker for LocalWorker {
 pub enum NetworkWorkerRequest {
     SetJob(Box<Job>),
     WorkUnit(WorkUnit),
+    // Skip a unit sent earlier if it has not been started, because
+    // another worker completed it.
+    Withdraw(WorkUnit),
     Done,
 }
 
 pub struct NetworkWorker {
     sender: Sender<WorkerRequest>,
     thread_handle: thread::JoinHandle<()>,
-    worker_info: WorkerInfo,
+    node: NodeHello,
+    encoding: WireEncoding,
+}
+
+// Read the node's events on their own thread, so that the network
+// worker can keep an eye on the work queue and on timeouts while it
+// waits for results. Results are marked as coming from the named
+// worker. The reader stops at the first error.
+fn spawn_reader(name: String, worker: String, stream: NetStream, encoding: WireEncoding)
+                -> io::Result<Receiver<io::Result<RenderEvent>>> {
+    let (s, r) = unbounded();
+
+    thread::Builder::new().name(name).spawn(move || {
+        if encoding.is_compact() {
+            forward_events(stream, &s, |ev: EncodedEvent| decode_event(ev, encoding).map(|ev| from_worker(ev, &worker)));
+        } else {
+            forward_events(stream, &s, |ev: RenderEvent| Ok(from_worker(ev, &worker)));
+        }
+    })?;
+
+    Ok(r)
+}
+
+fn from_worker(ev: RenderEvent, worker: &str) -> RenderEvent {
+    match ev {
+        RenderEvent::RowsReady(r) => RenderEvent::RowsReady(WorkUnitResult { worker: worker.to_string(), ..r }),
+        ev => ev,
+    }
+}
+
+fn forward_events<T, F>(stream: NetStream, events: &Sender<io::Result<RenderEvent>>, decode: F)
+    where T: DeserializeOwned, F: Fn(T) -> io::Result<RenderEvent> {
+    let stream_de: StreamDeserializer<'_, IoRead<NetStream>, T> =
+        StreamDeserializer::new(IoRead::new(stream));
+
+    for result in stream_de {
+        let ev = result.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+            .and_then(&decode);
+        let failed = ev.is_err();
+        if events.send(ev).is_err() || failed {
+            return;
+        }
+    }
+
+    events.send(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by node"))).ok();
+}
+
+// How quickly a node renders, for deciding how many work units to keep
+// queued on it.
+struct NodeThroughput {
+    num_threads: usize,
+    // The pixels in a full tile of the current job.
+    tile_pixels: usize,
+    // Smoothed time to render one pixel, once measured.
+    secs_per_pixel: Option<f64>,
+    last_result: Option<Instant>,
+}
+
+impl NodeThroughput {
+    fn new(num_threads: usize, tile_size: usize) -> Self {
+        Self {
+            num_threads,
+            tile_pixels: tile_size * tile_size,
+            secs_per_pixel: None,
+            last_result: None,
+        }
+    }
+
+    fn pipeline_depth(&self) -> usize {
+        let depth = match self.secs_per_pixel {
+            None => MIN_PIPELINE_DEPTH + self.num_threads / THREADS_PER_PREFETCH_UNIT,
+            Some(spp) => {
+                let tile_secs = spp * self.tile_pixels as f64;
+                1 + (NETWORK_PREFETCH_SECS / tile_secs).ceil() as usize
+            },
+        };
+        depth.clamp(MIN_PIPELINE_DEPTH, MAX_PIPELINE_DEPTH)
+    }
+
+    // Record the arrival of a result. The time since the previous result
+    // is only a measure of rendering time if the node had this unit
+    // queued all along.
+    fn record_result(&mut self, unit: &WorkUnit, node_was_busy: bool) {
+        let now = Instant::now();
+
+        if let (true, Some(last)) = (node_was_busy, self.last_result) {
+            let pixels = (unit.width() * unit.height()) as f64;
+            let spp = now.duration_since(last).as_secs_f64() / pixels;
+            self.secs_per_pixel = Some(match self.secs_per_pixel {
+                None => spp,
+                Some(old) => old + THROUGHPUT_SMOOTHING * (spp - old),
+            });
+        }
+
+        self.last_result = Some(now);
+    }
+}
+
+fn send_unit(stream: &mut NetStream, unit: WorkUnit, in_flight: &mut Vec<WorkUnit>) -> io::Result<()> {
+    d_println(format!("Network worker: sending work unit {:?}", unit));
+    write_message(stream, &NetworkWorkerRequest::WorkUnit(unit))?;
+    in_flight.push(unit);
+    Ok(())
+}
+
+// Run one job on the remote node, keeping as many work units in flight
+// as its throughput calls for. If a timeout is given, the node is lost
+// when it sends nothing for that long while it has units in flight. Any
+// error means the connection can no longer be trusted.
+fn run_network_job(stream: &mut NetStream, events: &Receiver<io::Result<RenderEvent>>,
+                   node: &NodeHello, job: Box<Job>, queue: &WorkQueueHandle,
+                   send_result: &Sender<Option<RenderEvent>>, timeout: Option<Duration>) -> io::Result<()> {
+    // A node that cannot build the scene sits this job out rather than
+    // failing partway through it
+    let missing = job.scene_data.features().missing_from(&node.features);
+    if !missing.is_empty() {
+        println!("Network node {} cannot render job {:?}, it does not support: {}",
+                 node.hostname, job.id, missing.join(", "));
+        return Ok(());
+    }
+
+    let mut throughput = NodeThroughput::new(node.worker_info.num_threads, job.config.tile_size);
+    write_message(stream, &NetworkWorkerRequest::SetJob(job))?;
+
+    // The units sent to the node whose results are still wanted. Results
+    // for any other units, such as those withdrawn or left over from an
+    // earlier job, are stale and ignored.
+    let mut in_flight: Vec<WorkUnit> = vec![];
+    // Whether the node has had work queued since the last result.
+    let mut busy = false;
+    let mut last_heard = Instant::now();
+
+    loop {
+        // Near the end of a job, other workers may finish copies of the
+        // units queued here
+        let (wanted, done_elsewhere): (Vec<WorkUnit>, Vec<WorkUnit>) =
+            in_flight.iter().partition(|u| queue.holds(u));
+        for unit in done_elsewhere {
+            d_println(format!("Network worker: withdrawing work unit {:?}", unit));
+            write_message(stream, &NetworkWorkerRequest::Withdraw(unit))?;
+        }
+        in_flight = wanted;
+
+        let depth = throughput.pipeline_depth();
+        while in_flight.len() < depth {
+            match queue.try_next() {
+                None => break,
+                Some(unit) => {
+                    if in_flight.is_empty() {
+                        last_heard = Instant::now();
+                    }
+                    send_unit(stream, unit, &mut in_flight)?;
+                },
+            }
+        }
+
+        // With nothing in flight, wait for more work or the end of the
+        // job
+        if in_flight.is_empty() {
+            match queue.next() {
+                None => break,
+                Some(unit) => {
+                    last_heard = Instant::now();
+                    send_unit(stream, unit, &mut in_flight)?;
+                },
+            }
+        }
+
+        let ev = match events.recv_timeout(NETWORK_POLL_INTERVAL) {
+            Ok(ev) => ev?,
+            Err(RecvTimeoutError::Timeout) => {
+                if timeout.map_or(false, |t| last_heard.elapsed() >= t) {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a result from the node"));
+                }
+                continue;
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by node"));
+            },
+        };
+
+        d_println(format!("Network worker got a render event from the remote end"));
+        last_heard = Instant::now();
+
+        if let RenderEvent::RowsReady(r) = &ev {
+            let unit = r.work_unit;
+            let i = match in_flight.iter().position(|u| *u == unit) {
+                Some(i) => i,
+                None => {
+                    d_println(format!("Network worker: ignoring stale result for {:?}", unit));
+                    continue;
+                },
+            };
+
+            in_flight.remove(i);
+            throughput.record_result(&unit, busy);
+            busy = !in_flight.is_empty();
+
+            if !queue.complete(&unit) {
+                d_println(format!("Network worker: discarding duplicate result for {:?}", unit));
+                continue;
+            }
+        }
+
+        if send_result.send(Some(ev)).is_err() {
+            d_println(format!("Network worker could not deliver a result, discarding it"));
+        }
+    }
+
+    d_println(format!("NetworkWorker sending Done message"));
+    write_message(stream, &NetworkWorkerRequest::Done)
+}
+
+// Exchange hellos with a node, returning the node's hello and the
+// encoding it will send results in if both ends speak the same protocol
+// and the node accepts our credentials. A node that does not support
+// the requested encoding sends results as they are.
+fn handshake(stream: &mut NetStream, endpoint: &str, secret: Option<&[u8]>,
+             encoding: WireEncoding) -> io::Result<(NodeHello, WireEncoding)> {
+    let node: NodeHello = read_message(stream)
+        .map_err(|e| io::Error::new(e.kind(), format!("no valid hello from {}: {}", endpoint, e)))?;
+
+    check_protocol_version(&format!("node {} ({})", endpoint, node.hostname),
+                           node.protocol_version, &node.flux_version)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let response = match (&node.auth_challenge, secret) {
+        (None, _) => None,
+        (Some(challenge), Some(secret)) => Some(auth_response(secret, challenge)),
+        (Some(_), None) => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("node {} requires a shared secret", endpoint)));
+        },
+    };
+
+    let encoding = if node.supports(&encoding) {
+        encoding
+    } else {
+        println!("Node {} cannot send results as {:?}, using the default encoding", endpoint, encoding);
+        WireEncoding::default()
+    };
+
+    write_message(stream, &ManagerHello::new(response, encoding))?;
+
+    match read_message(stream)? {
+        NodeGreeting::Accepted => Ok((node, encoding)),
+        NodeGreeting::Refused { reason } => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               format!("node {} refused the connection: {}", endpoint, reason)))
+        },
+    }
 }
 
 impl NetworkWorker {
-    pub fn new(raw_endpoint: &String) -> Result<Self, io::Error> {
+    // Connect to a flux-node process. If a timeout is given, the node is
+    // considered lost when it sends nothing for that long while it has
+    // work units in flight, so it must exceed the time needed to render
+    // a single work unit.
+    pub fn new(raw_endpoint: &String, timeout: Option<Duration>,
+               security: &ClientSecurity, encoding: WireEncoding) -> Result<Self, io::Error> {
         let endpoint = match raw_endpoint.find(':') {
             None => format!("{}:{}", raw_endpoint, DEFAULT_PORT),
             Some(_) => raw_endpoint.clone(),
         };
 
         let tname = format!("NetworkWorker({})", endpoint);
-        match TcpStream::connect(endpoint.as_str()) {
-            Err(e) => Err(e),
-            Ok(st) => {
-                let stream_clone = st.try_clone().unwrap();
-                let mut stream_info_de: StreamDeserializer<'_, IoRead<TcpStream>, WorkerInfo> =
-                    StreamDeserializer::new(IoRead::new(stream_clone));
-
-                println!("Getting info");
-                // Expect that the first thing to do is read a usize
-                // from the network stream indicating the number of
-                // threads that the remote end will be using.
-                let worker_info: WorkerInfo = match stream_info_de.next() {
-                    Some(Ok(i)) => i,
-                    _ => {
-                        panic!("Could not get info from network node")
-                    },
-                };
-
-                println!("Got info");
-
-                let (s, r): (Sender<WorkerRequest>, Receiver<WorkerRequest>) = unbounded();
-
-                let handle = thread::Builder::new().name(tname).spawn(move || {
-                    let mut my_stream = st;
-                    let stream_clone = my_stream.try_clone().unwrap();
-                    let mut stream_de: StreamDeserializer<'_, IoRead<TcpStream>, RenderEvent> =
-                        StreamDeserializer::new(IoRead::new(stream_clone));
-
-                    while let Ok(Some((job_boxed, recv_unit, send_result, wg))) = r.recv() {
-                        let job = *job_boxed;
-
-                        d_println(format!("Network worker: got job {:?}", job.id));
-
-                        to_writer(&mut my_stream, &NetworkWorkerRequest::SetJob(Box::new(job))).unwrap();
-
-                        let buf = 2;
-                        let mut sent = 0;
-
-                        for _ in 0..buf {
-                            match recv_unit.recv() {
-                                Err(e) => {
-                                    d_println(format!("Error sending initial work unit: {}", e));
-                                }
-                                Ok(unit) => {
-                                    d_println(format!("Sending initial work unit"));
-                                    to_writer(&mut my_stream, &NetworkWorkerRequest::WorkUnit(unit)).unwrap();
-                                    sent += 1;
-                                },
-                            };
-                        }
-
-                        d_println(format!("NetworkWorker sending remaining work units"));
-
-                        while let Ok(unit) = recv_unit.recv() {
-                            d_println(format!("Network worker: got work unit {:?}", unit));
-
-                            to_writer(&mut my_stream, &NetworkWorkerRequest::WorkUnit(unit)).unwrap();
-
-                            match stream_de.next() {
-                                None => {
-                                    d_println("Stream deserializer iterator finished".to_string());
-                                },
-                                Some(result) => {
-                                    match result {
-                                        Ok(ev) => {
-                                            d_println(format!("Network worker got a render event from the remote end"));
-                                            send_result.send(Some(ev)).unwrap();
-                                        },
-                                        Err(e) => {
-                                            d_println(format!("Network worker got error from deserializer: {}", e));
-                                            return;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        d_println(format!("NetworkWorker collecting final {} results", sent));
-
-                        for _ in 0..sent {
-                            match stream_de.next() {
-                                None => {
-                                    d_println("Stream deserializer iterator finished".to_string());
-                                },
-                                Some(result) => {
-                                    match result {
-                                        Ok(ev) => {
-                                            send_result.send(Some(ev)).unwrap();
-                                        },
-                                        Err(e) => {
-                                            d_println(format!("Network worker got error from deserializer: {}", e));
-                                            return;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        d_println(format!("NetworkWorker sending Done message"));
-
-                        to_writer(&mut my_stream, &NetworkWorkerRequest::Done).unwrap();
+        let socket = TcpStream::connect(endpoint.as_str())?;
+        // Only the handshake reads with a timeout; afterwards the worker
+        // keeps track of how long the node has been silent
+        socket.set_read_timeout(timeout)?;
+
+        let mut st = match &security.tls {
+            None => NetStream::plain(socket),
+            Some(config) => {
+                let host = &endpoint[..endpoint.rfind(':').unwrap()];
+                NetStream::connect_tls(socket, config, host)
+                    .map_err(|e| io::Error::new(e.kind(), format!("TLS connection to {} failed: {}", endpoint, e)))?
+            },
+        };
 
+        let (node, encoding) = handshake(&mut st, &endpoint, security.secret.as_deref(), encoding).map_err(|e| {
+            // A TLS node says nothing until it gets a TLS handshake
+            let waiting = e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut;
+            if waiting && security.tls.is_none() {
+                io::Error::new(e.kind(), format!("{} (does the node require TLS?)", e))
+            } else {
+                e
+            }
+        })?;
+
+        st.socket().set_read_timeout(None)?;
+        let events = spawn_reader(format!("{} reader", tname), endpoint.clone(), st.try_clone()?, encoding)?;
+        let (s, r): (Sender<WorkerRequest>, Receiver<WorkerRequest>) = unbounded();
+
+        let thread_node = node.clone();
+        let handle = thread::Builder::new().name(tname).spawn(move || {
+            let mut my_stream = st;
+
+            while let Ok(Some((job, queue, send_result, wg))) = r.recv() {
+                d_println(format!("Network worker: got job {:?}", job.id));
+
+                match run_network_job(&mut my_stream, &events, &thread_node, job, &queue, &send_result, timeout) {
+                    Ok(()) => {
                         d_println(format!("Network worker finished job"));
+                    },
+                    Err(e) => {
+                        // Give this worker's units to the others and
+                        // shut down; the manager skips workers that are
+                        // no longer running.
+                        let num_requeued = queue.fail();
+                        println!("Lost network node {}: {}; requeued {} work unit{}",
+                                 endpoint, e, num_requeued, if num_requeued == 1 { "" } else { "s" });
+                        my_stream.socket().shutdown(Shutdown::Both).ok();
                         drop(wg);
-                    }
-
-                    d_println(format!("Network worker shutting down"));
-                }).unwrap();
+                        return;
+                    },
+                }
 
-                Ok(Self {
-                    sender: s,
-                    thread_handle: handle,
-                    worker_info,
-                })
+                drop(wg);
             }
-        }
+
+            d_println(format!("Network worker shutting down"));
+            my_stream.socket().shutdown(Shutdown::Both).ok();
+        })?;
+
+        Ok(Self {
+            sender: s,
+            thread_handle: handle,
+            node,
+            encoding,
+        })
+    }
+}
+
+impl NetworkWorker {
+    // The hello the node sent when we connected.
+    pub fn node(&self) -> &NodeHello {
+        &self.node
+    }
+
+    // The encoding the node sends results in.
+    pub fn encoding(&self) -> WireEncoding {
+        self.encoding
     }
 }
 
@@ -253,6 +520,6 @@ impl Worker for NetworkWorker {
     }
 
     fn info(&self) -> WorkerInfo {
-        self.worker_info
+        self.node.worker_info
     }
 }
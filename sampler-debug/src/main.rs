@@ -3,6 +3,7 @@ use nalgebra::Vector3;
 
 use fluxcore::color::Color;
 use fluxcore::image::Image;
+use fluxcore::tonemap::ToneMapping;
 use samplers;
 
 use clap::{App, Arg};
@@ -23,6 +24,9 @@ fn plot_hemi_sample(i: &mut Image, sample: Vector3<f64>) {
 }
 
 fn plot(base: Vec<samplers::UnitSquareSample>, basename: &str) {
+    // Plot the sample values directly, without any display encoding
+    let linear = ToneMapping { srgb: false, ..ToneMapping::default() };
+
     let mut i1 = Image::new(100, 100);
     for sample in base.clone() {
         plot_2d_sample(&mut i1, sample);
@@ -30,7 +34,7 @@ fn plot(base: Vec<samplers::UnitSquareSample>, basename: &str) {
 
     let path1 = format!("sampler-debug-{}.ppm", basename);
     let mut output_file = File::create(path1.clone()).unwrap();
-    i1.write(&mut output_file);
+    i1.write(&mut output_file, &linear);
     println!("Wrote output to {}", path1);
 
     let mut i2 = Image::new(100, 100);
@@ -41,7 +45,7 @@ fn plot(base: Vec<samplers::UnitSquareSample>, basename: &str) {
 
     let path2 = format!("sampler-debug-{}-hemi.ppm", basename);
     let mut output_file = File::create(path2.clone()).unwrap();
-    i2.write(&mut output_file);
+    i2.write(&mut output_file, &linear);
     println!("Wrote output to {}", path2);
 }
 